@@ -0,0 +1,153 @@
+//! Benchmarks of `resample_3d_nifti` against the previous implementation,
+//! which built a dense 4xN matrix of target voxel coordinates and resampled
+//! on a single thread. Run with `cargo bench --bench resample`.
+//!
+//! The source is a 2 mm MNI-sized grid (91 x 109 x 91) and the target a 1 mm
+//! MNI-sized grid (182 x 218 x 182), i.e. about 7.2 million target voxels.
+
+use itertools::iproduct;
+use ndarray::prelude::*;
+use ndarray_linalg::solve::Inverse;
+use nirust::{resample_3d_nifti, resample_4d_nifti, Interpolation};
+use std::time::{Duration, Instant};
+
+const SOURCE_SHAPE: (usize, usize, usize) = (91, 109, 91);
+const TARGET_SHAPE: (usize, usize, usize) = (182, 218, 182);
+const N_RUNS: u32 = 3;
+
+fn main() {
+    let source = Array::from_shape_fn(SOURCE_SHAPE, |(i, j, k)| {
+        ((i * 7 + j * 3 + k) % 50) as f32
+    });
+    let source_affine = arr2(&[
+        [-2., 0., 0., 90.],
+        [0., 2., 0., -126.],
+        [0., 0., 2., -72.],
+        [0., 0., 0., 1.],
+    ]);
+    let target_affine = arr2(&[
+        [-1., 0., 0., 90.],
+        [0., 1., 0., -126.],
+        [0., 0., 1., -72.],
+        [0., 0., 0., 1.],
+    ]);
+    let n_target = TARGET_SHAPE.0 * TARGET_SHAPE.1 * TARGET_SHAPE.2;
+    println!(
+        "resampling {:?} to {:?} ({} target voxels)",
+        SOURCE_SHAPE, TARGET_SHAPE, n_target
+    );
+    println!(
+        "output: {} MB, coordinate matrix of the legacy implementation: {} MB",
+        n_target * 4 / 1_000_000,
+        4 * n_target * 4 / 1_000_000
+    );
+
+    let legacy = _bench("legacy nearest", || {
+        _legacy_resample(&source, &source_affine, &target_affine, TARGET_SHAPE)
+    });
+    let nearest = _bench("nearest", || {
+        resample_3d_nifti(
+            &source,
+            &source_affine,
+            &target_affine,
+            TARGET_SHAPE,
+            Interpolation::Nearest,
+            0.,
+        )
+        .unwrap()
+    });
+    assert_eq!(legacy, nearest, "implementations disagree");
+
+    for interpolation in [Interpolation::Trilinear, Interpolation::Cubic] {
+        _bench(&format!("{:?}", interpolation).to_lowercase(), || {
+            resample_3d_nifti(
+                &source,
+                &source_affine,
+                &target_affine,
+                TARGET_SHAPE,
+                interpolation,
+                0.,
+            )
+            .unwrap()
+        });
+    }
+
+    let series = source.clone().insert_axis(Axis(3));
+    let series = ndarray::concatenate(Axis(3), &[series.view(); 10]).unwrap();
+    _bench("nearest, 10 volumes", || {
+        resample_4d_nifti(
+            &series,
+            &source_affine,
+            &target_affine,
+            TARGET_SHAPE,
+            Interpolation::Nearest,
+            0.,
+        )
+        .unwrap()
+    });
+}
+
+// Run `f` a few times and print the fastest run.
+fn _bench<T, F: Fn() -> T>(name: &str, f: F) -> T {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..N_RUNS {
+        let start = Instant::now();
+        let output = f();
+        best = best.min(start.elapsed());
+        result = Some(output);
+    }
+    println!("{:<24} {:>10.1} ms", name, best.as_secs_f64() * 1000.);
+    result.unwrap()
+}
+
+// The previous implementation of nearest neighbour resampling, with a dense
+// 4xN matrix of target coordinates and a single-threaded loop.
+fn _legacy_resample(
+    source: &Array3<f32>,
+    source_affine: &Array2<f32>,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+) -> Array3<f32> {
+    let mut resampled_data: Array<f32, Ix3> = Array::zeros(target_shape);
+    let (x_dim_src, y_dim_src, z_dim_src) = source.dim();
+    let (x_dim_targ, y_dim_targ, z_dim_targ) = target_shape;
+
+    let mut target_indices = Array::zeros((4, resampled_data.len()));
+    for (row, (i, j, k)) in
+        iproduct!(0..x_dim_targ, 0..y_dim_targ, 0..z_dim_targ).enumerate()
+    {
+        target_indices
+            .slice_mut(s![.., row])
+            .assign(&array!(i as f32, j as f32, k as f32, 1.));
+    }
+    let source_indices = source_affine
+        .inv()
+        .unwrap()
+        .dot(target_affine)
+        .dot(&target_indices);
+
+    let round = |x: f32, n: usize| {
+        let x = x.round();
+        (x >= 0. && x < n as f32).then_some(x as usize)
+    };
+    for (col_src, col_targ) in source_indices
+        .axis_iter(Axis(1))
+        .zip(target_indices.axis_iter(Axis(1)))
+    {
+        let target = [
+            col_targ[0] as usize,
+            col_targ[1] as usize,
+            col_targ[2] as usize,
+        ];
+        resampled_data[target] = match (
+            round(col_src[0], x_dim_src),
+            round(col_src[1], y_dim_src),
+            round(col_src[2], z_dim_src),
+        ) {
+            (Some(i), Some(j), Some(k)) => source[[i, j, k]],
+            _ => 0.,
+        };
+    }
+    resampled_data
+}
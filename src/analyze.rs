@@ -0,0 +1,555 @@
+//! The `nirust::analyze` module reads and writes images stored as a pair of
+//! a header file (`.hdr`) and an image file (`.img`), which is used by the
+//! Analyze 7.5 format as well as by NIfTI-1 and NIfTI-2 pairs. Either file
+//! can be compressed with gzip (`.hdr.gz`, `.img.gz`).
+//!
+//! Analyze 7.5 headers have no reliable orientation information. As in
+//! nibabel, the image is assumed to be stored in radiological (LAS)
+//! orientation, i.e. the fall-back affine with a flipped x axis is used. If
+//! an SPM origin is set in the `originator` field, it is used as the center
+//! of the affine. Both are assumptions that can swap left and right, and
+//! are therefore reported as warnings.
+
+use log::{info, warn};
+use ndarray::prelude::*;
+use nifti::NiftiHeader;
+use std::path::{Path, PathBuf};
+
+use crate::codec::{
+    read_bytes, read_head, write_bytes, HeaderBuilder, HeaderFields,
+    ParsedHeader,
+};
+use crate::error::{NirustError, Result};
+use crate::image::{Dtype, ImageFormat};
+use crate::nifti2::{
+    encode_nifti2_header, parse_nifti2_header, NIFTI1_MAX_DIM,
+    NIFTI2_HEADER_SIZE,
+};
+
+/// Size of the NIfTI-1 and Analyze 7.5 headers in bytes.
+pub const NIFTI1_HEADER_SIZE: usize = 348;
+
+/// Whether a path refers to one file of a header/image pair, i.e. ends with
+/// `.hdr`, `.img`, `.hdr.gz` or `.img.gz`.
+pub fn is_pair_path(path: &Path) -> bool {
+    _split_pair_path(path).is_some()
+}
+
+/// Paths of the header and the image file of a pair, given the path of
+/// either of them. Files that exist are preferred, so that a compressed
+/// header can be paired with an uncompressed image file and vice versa.
+pub fn pair_paths(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let (stem, gz) = _split_pair_path(path)?;
+    let candidate = |ext: &str| {
+        let plain = PathBuf::from(format!("{}.{}", stem, ext));
+        let compressed = PathBuf::from(format!("{}.{}.gz", stem, ext));
+        match (plain.exists(), compressed.exists(), gz) {
+            (true, false, _) => plain,
+            (false, true, _) => compressed,
+            (_, _, true) => compressed,
+            (_, _, false) => plain,
+        }
+    };
+    Some((candidate("hdr"), candidate("img")))
+}
+
+// Split a pair path into the path without extension and whether it is
+// compressed.
+fn _split_pair_path(path: &Path) -> Option<(String, bool)> {
+    let path = path.to_str()?;
+    let gz = path.ends_with(".gz");
+    let path = path.strip_suffix(".gz").unwrap_or(path);
+    let stem = path
+        .strip_suffix(".hdr")
+        .or_else(|| path.strip_suffix(".img"))?;
+    Some((stem.to_string(), gz))
+}
+
+/// Read a pair of header and image files.
+///
+/// Returns the header converted to NIfTI-1, the values as they are stored
+/// on disk, i.e. without applying `scl_slope` and `scl_inter`, and the
+/// format of the header.
+pub fn read_pair(
+    path: &Path,
+) -> Result<(NiftiHeader, Array<f32, IxDyn>, ImageFormat)> {
+    let (hdr_path, img_path) = pair_paths(path).ok_or_else(|| {
+        NirustError::Argument(format!("{:?} is not a .hdr or .img file", path))
+    })?;
+    info!("Reading header {:?} and image {:?}", hdr_path, img_path);
+    let hdr_bytes = read_bytes(&hdr_path)?;
+    let head = read_head(&hdr_path, 4)?;
+    let is_nifti2 = head.len() == 4
+        && (i32::from_le_bytes([head[0], head[1], head[2], head[3]])
+            == NIFTI2_HEADER_SIZE as i32
+            || i32::from_be_bytes([head[0], head[1], head[2], head[3]])
+                == NIFTI2_HEADER_SIZE as i32);
+
+    let (parsed, format) = if is_nifti2 {
+        (
+            parse_nifti2_header(&hdr_bytes, &hdr_path)?,
+            ImageFormat::Nifti2,
+        )
+    } else {
+        parse_nifti1_header(&hdr_bytes, &hdr_path)?
+    };
+    if format != ImageFormat::Analyze && &parsed.header.magic != b"ni1\0" {
+        return Err(NirustError::Format(format!(
+            "{:?} is the header of a single-file NIfTI image",
+            hdr_path
+        )));
+    }
+    let img_bytes = read_bytes(&img_path)?;
+    let data = parsed.decode_data(&img_bytes)?;
+    Ok((parsed.header, data, format))
+}
+
+/// Parse a NIfTI-1 or Analyze 7.5 header.
+///
+/// Analyze headers are converted to NIfTI-1 headers: the SPM scale factor is
+/// used as `scl_slope`, and the SPM origin, if set, is stored as sform.
+pub(crate) fn parse_nifti1_header(
+    bytes: &[u8],
+    path: &Path,
+) -> Result<(ParsedHeader, ImageFormat)> {
+    if bytes.len() < NIFTI1_HEADER_SIZE {
+        return Err(_invalid(path, "file is shorter than the header"));
+    }
+    let big_endian =
+        i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            != NIFTI1_HEADER_SIZE as i32;
+    let fields = HeaderFields { bytes, big_endian };
+    if fields.i32(0) != NIFTI1_HEADER_SIZE as i32 {
+        return Err(_invalid(path, "header size must be 348 bytes"));
+    }
+
+    let n_dims = fields.i16(40);
+    if !(1..=7).contains(&n_dims) {
+        return Err(_invalid(path, "number of dimensions must be 1 to 7"));
+    }
+    let mut dim = [1u16; 8];
+    dim[0] = n_dims as u16;
+    for (axis, d) in dim.iter_mut().enumerate().take(n_dims as usize + 1) {
+        if axis > 0 {
+            *d = fields.i16(40 + 2 * axis).max(1) as u16;
+        }
+    }
+    let shape: Vec<usize> = dim[1..=n_dims as usize]
+        .iter()
+        .map(|&n| n as usize)
+        .collect();
+
+    let datatype = fields.i16(70);
+    let dtype = Dtype::from_code(datatype).ok_or_else(|| {
+        _invalid(path, &format!("datatype {} is not supported", datatype))
+    })?;
+    let mut pixdim = [0f32; 8];
+    for (axis, p) in pixdim.iter_mut().enumerate() {
+        *p = fields.f32(76 + 4 * axis);
+    }
+    let row = |offset: usize| {
+        [
+            fields.f32(offset),
+            fields.f32(offset + 4),
+            fields.f32(offset + 8),
+            fields.f32(offset + 12),
+        ]
+    };
+
+    let magic: [u8; 4] = bytes[344..348].try_into().unwrap();
+    let is_nifti = &magic == b"ni1\0" || &magic == b"n+1\0";
+    let mut header = NiftiHeader {
+        dim,
+        datatype,
+        bitpix: fields.i16(72),
+        pixdim,
+        vox_offset: fields.f32(108),
+        scl_slope: fields.f32(112),
+        descrip: bytes[148..228].to_vec(),
+        aux_file: bytes[228..252].try_into().unwrap(),
+        glmax: fields.i32(140),
+        glmin: fields.i32(144),
+        cal_max: fields.f32(124),
+        cal_min: fields.f32(128),
+        ..NiftiHeader::default()
+    };
+
+    let format = if is_nifti {
+        header.dim_info = bytes[39];
+        header.intent_p1 = fields.f32(56);
+        header.intent_p2 = fields.f32(60);
+        header.intent_p3 = fields.f32(64);
+        header.intent_code = fields.i16(68);
+        header.slice_start = fields.i16(74);
+        header.scl_inter = fields.f32(116);
+        header.slice_end = fields.i16(120);
+        header.slice_code = bytes[122];
+        header.xyzt_units = bytes[123];
+        header.slice_duration = fields.f32(132);
+        header.toffset = fields.f32(136);
+        header.qform_code = fields.i16(252);
+        header.sform_code = fields.i16(254);
+        header.quatern_b = fields.f32(256);
+        header.quatern_c = fields.f32(260);
+        header.quatern_d = fields.f32(264);
+        header.quatern_x = fields.f32(268);
+        header.quatern_y = fields.f32(272);
+        header.quatern_z = fields.f32(276);
+        header.srow_x = row(280);
+        header.srow_y = row(296);
+        header.srow_z = row(312);
+        header.intent_name = bytes[328..344].try_into().unwrap();
+        header.magic = magic;
+        ImageFormat::Nifti1
+    } else {
+        info!("No NIfTI magic code found, reading Analyze 7.5 header");
+        _convert_analyze_orientation(&mut header, &fields, path);
+        header.magic = *b"ni1\0";
+        ImageFormat::Analyze
+    };
+
+    let parsed = ParsedHeader {
+        header,
+        shape,
+        dtype,
+        big_endian,
+        vox_offset: fields.f32(108).max(0.) as usize,
+    };
+    Ok((parsed, format))
+}
+
+// Analyze headers have no sform or qform. Use the SPM origin if it is set,
+// and the fall-back affine otherwise.
+fn _convert_analyze_orientation(
+    header: &mut NiftiHeader,
+    fields: &HeaderFields,
+    path: &Path,
+) {
+    header.qform_code = 0;
+    header.sform_code = 0;
+    header.scl_inter = 0.;
+
+    if fields.bytes[252] != 0 {
+        warn!(
+            "Ignoring the 'orient' field ({}) of the Analyze header {:?}!",
+            fields.bytes[252], path
+        );
+    }
+    // the SPM origin is stored as 1-based voxel indices in 'originator'
+    let origin = [fields.i16(253), fields.i16(255), fields.i16(257)];
+    if origin.iter().all(|&o| o == 0) {
+        warn!(
+            "Analyze header {:?} has no orientation information, assuming \
+            radiological (LAS) storage. Left and right may be swapped!",
+            path
+        );
+        return;
+    }
+    warn!(
+        "Analyze header {:?} has no orientation information, assuming \
+        radiological (LAS) storage with the SPM origin {:?}. Left and right \
+        may be swapped!",
+        path, origin
+    );
+    let zoom = |axis: usize| {
+        let z = header.pixdim[axis + 1];
+        if z == 0. {
+            1.
+        } else {
+            z
+        }
+    };
+    let zooms = [-zoom(0), zoom(1), zoom(2)];
+    let mut rows = [[0f32; 4]; 3];
+    for axis in 0..3 {
+        rows[axis][axis] = zooms[axis];
+        rows[axis][3] = -(origin[axis] as f32 - 1.) * zooms[axis];
+    }
+    header.srow_x = rows[0];
+    header.srow_y = rows[1];
+    header.srow_z = rows[2];
+    header.sform_code = 2;
+}
+
+/// Write a pair of header and image files in little endian byte order.
+///
+/// The header is written in the given format. NIfTI-1 and Analyze headers
+/// are replaced by a NIfTI-2 header if any dimension exceeds the NIfTI-1
+/// limit. Analyze headers cannot store the orientation of the image, only
+/// voxel sizes and the SPM origin derived from the affine are kept.
+///
+/// Parameters
+/// ----------
+/// path : Path of the header or the image file.
+///
+/// header : NIfTI-1 header with the metadata of the image.
+///
+/// shape : Shape of the image data, which takes precedence over the
+/// dimensions in the header.
+///
+/// data : Image data already encoded with the datatype of the header.
+///
+/// format : Format of the header.
+///
+/// affine : Affine of the image, used to derive the SPM origin for Analyze
+/// headers.
+///
+pub fn write_pair(
+    path: &Path,
+    header: &NiftiHeader,
+    shape: &[usize],
+    data: &[u8],
+    format: ImageFormat,
+    affine: &Array2<f32>,
+) -> Result<()> {
+    let (stem, gz) = _split_pair_path(path).ok_or_else(|| {
+        NirustError::Argument(format!("{:?} is not a .hdr or .img file", path))
+    })?;
+    let ext = if gz { ".gz" } else { "" };
+    let hdr_path = PathBuf::from(format!("{}.hdr{}", stem, ext));
+    let img_path = PathBuf::from(format!("{}.img{}", stem, ext));
+
+    let exceeds_nifti1 = shape.iter().any(|&n| n > NIFTI1_MAX_DIM);
+    let hdr_bytes = match format {
+        ImageFormat::Nifti2 => encode_nifti2_header(header, shape, true),
+        _ if exceeds_nifti1 => encode_nifti2_header(header, shape, true),
+        ImageFormat::Nifti1 | ImageFormat::Mgh => {
+            encode_nifti1_header(header, shape, true)
+        }
+        ImageFormat::Analyze => encode_analyze_header(header, shape, affine),
+    };
+    info!("Writing header {:?} and image {:?}", hdr_path, img_path);
+    write_bytes(&hdr_path, &hdr_bytes)?;
+    write_bytes(&img_path, data)
+}
+
+/// Encode a NIfTI-1 header in little endian byte order, either for a single
+/// file (image data at byte 352) or for a pair of header and image files.
+pub(crate) fn encode_nifti1_header(
+    header: &NiftiHeader,
+    shape: &[usize],
+    is_pair: bool,
+) -> Vec<u8> {
+    let mut builder = _encode_common(header, shape);
+    let mut put = |offset: usize, value: &[u8]| builder.put(offset, value);
+    let (magic, vox_offset) = if is_pair {
+        (b"ni1\0", 0.)
+    } else {
+        (b"n+1\0", 352f32)
+    };
+    put(39, &[header.dim_info]);
+    put(56, &header.intent_p1.to_le_bytes());
+    put(60, &header.intent_p2.to_le_bytes());
+    put(64, &header.intent_p3.to_le_bytes());
+    put(68, &header.intent_code.to_le_bytes());
+    put(74, &header.slice_start.to_le_bytes());
+    put(108, &vox_offset.to_le_bytes());
+    put(116, &header.scl_inter.to_le_bytes());
+    put(120, &header.slice_end.to_le_bytes());
+    put(122, &[header.slice_code, header.xyzt_units]);
+    put(132, &header.slice_duration.to_le_bytes());
+    put(136, &header.toffset.to_le_bytes());
+    put(252, &header.qform_code.to_le_bytes());
+    put(254, &header.sform_code.to_le_bytes());
+    let quatern = [
+        header.quatern_b,
+        header.quatern_c,
+        header.quatern_d,
+        header.quatern_x,
+        header.quatern_y,
+        header.quatern_z,
+    ];
+    for (i, q) in quatern.iter().enumerate() {
+        put(256 + 4 * i, &q.to_le_bytes());
+    }
+    for (offset, row) in [
+        (280, header.srow_x),
+        (296, header.srow_y),
+        (312, header.srow_z),
+    ] {
+        for (i, r) in row.iter().enumerate() {
+            put(offset + 4 * i, &r.to_le_bytes());
+        }
+    }
+    put(328, &header.intent_name);
+    put(344, magic);
+    builder.bytes
+}
+
+/// Encode an Analyze 7.5 header in little endian byte order. The SPM origin
+/// is derived from the affine, which is only possible if it is diagonal.
+pub(crate) fn encode_analyze_header(
+    header: &NiftiHeader,
+    shape: &[usize],
+    affine: &Array2<f32>,
+) -> Vec<u8> {
+    let mut builder = _encode_common(header, shape);
+    // 'regular' is always 'r' in Analyze headers
+    builder.put(38, b"r");
+
+    let is_diagonal = (0..3)
+        .all(|row| (0..3).all(|col| row == col || affine[[row, col]] == 0.));
+    if is_diagonal && (0..3).all(|axis| affine[[axis, axis]] != 0.) {
+        for axis in 0..3 {
+            let origin = 1. - affine[[axis, 3]] / affine[[axis, axis]];
+            let origin = origin.round() as i16;
+            builder.put(253 + 2 * axis, &origin.to_le_bytes());
+        }
+        if affine[[0, 0]] > 0. {
+            warn!(
+                "Analyze images are assumed to be stored in radiological \
+                orientation, left and right will be swapped when reading \
+                the image!"
+            );
+        }
+    } else {
+        warn!(
+            "The affine cannot be stored in an Analyze header, only the \
+            voxel sizes are kept!"
+        );
+    }
+    builder.bytes
+}
+
+// Fields that are shared by NIfTI-1 and Analyze headers.
+fn _encode_common(header: &NiftiHeader, shape: &[usize]) -> HeaderBuilder {
+    let mut builder = HeaderBuilder::new(NIFTI1_HEADER_SIZE);
+    let mut put = |offset: usize, value: &[u8]| builder.put(offset, value);
+    put(0, &(NIFTI1_HEADER_SIZE as i32).to_le_bytes());
+    put(32, &16384i32.to_le_bytes());
+    put(40, &(shape.len() as i16).to_le_bytes());
+    for axis in 1..8 {
+        let n = shape.get(axis - 1).copied().unwrap_or(1);
+        put(40 + 2 * axis, &(n.min(NIFTI1_MAX_DIM) as i16).to_le_bytes());
+    }
+    put(70, &header.datatype.to_le_bytes());
+    put(72, &header.bitpix.to_le_bytes());
+    for (axis, p) in header.pixdim.iter().enumerate() {
+        put(76 + 4 * axis, &p.to_le_bytes());
+    }
+    put(112, &header.scl_slope.to_le_bytes());
+    put(124, &header.cal_max.to_le_bytes());
+    put(128, &header.cal_min.to_le_bytes());
+    put(148, &header.descrip[..header.descrip.len().min(80)]);
+    put(228, &header.aux_file);
+    builder
+}
+
+fn _invalid(path: &Path, msg: &str) -> NirustError {
+    NirustError::Format(format!(
+        "{:?} is not a valid NIfTI-1 or Analyze header: {}",
+        path, msg
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::get_affine;
+
+    fn _header() -> NiftiHeader {
+        NiftiHeader {
+            dim: [3, 4, 3, 2, 1, 1, 1, 1],
+            datatype: Dtype::Int16.code(),
+            bitpix: 16,
+            pixdim: [-1., 2., 2.5, 3., 1., 1., 1., 1.],
+            scl_slope: 0.5,
+            scl_inter: 10.,
+            qform_code: 1,
+            quatern_b: 0.,
+            quatern_c: 0.,
+            quatern_d: 1.,
+            quatern_x: -4.,
+            quatern_y: 5.,
+            quatern_z: -6.,
+            sform_code: 4,
+            srow_x: [-2., 0., 0., 4.],
+            srow_y: [0., 2.5, 0., -5.],
+            srow_z: [0., 0., 3., 6.],
+            ..NiftiHeader::default()
+        }
+    }
+
+    // int16 data and the values expected when reading them, which are
+    // stored in Fortran order
+    fn _data() -> (Vec<u8>, Array<f32, IxDyn>) {
+        let values: Vec<i16> = (0..24).map(|v| v * 3 - 20).collect();
+        let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let expected = Array::from_shape_vec((4, 3, 2).f(), values)
+            .unwrap()
+            .mapv(|v| v as f32)
+            .into_dyn();
+        (bytes, expected)
+    }
+
+    #[test]
+    fn nifti1_pair_round_trip() {
+        let path = std::env::temp_dir().join("nirust_analyze_test_ni1.hdr");
+        let header = _header();
+        let (bytes, expected) = _data();
+        write_pair(
+            &path,
+            &header,
+            &[4, 3, 2],
+            &bytes,
+            ImageFormat::Nifti1,
+            &get_affine(&header),
+        )
+        .unwrap();
+        let (read, data, format) = read_pair(&path).unwrap();
+
+        assert_eq!(format, ImageFormat::Nifti1);
+        assert_eq!(read.dim, header.dim);
+        assert_eq!(read.pixdim, header.pixdim);
+        assert_eq!((read.datatype, read.bitpix), (4, 16));
+        assert_eq!((read.scl_slope, read.scl_inter), (0.5, 10.));
+        assert_eq!((read.qform_code, read.sform_code), (1, 4));
+        assert_eq!(
+            [read.quatern_b, read.quatern_c, read.quatern_d],
+            [0., 0., 1.]
+        );
+        assert_eq!(
+            [read.quatern_x, read.quatern_y, read.quatern_z],
+            [-4., 5., -6.]
+        );
+        assert_eq!(
+            [read.srow_x, read.srow_y, read.srow_z],
+            [header.srow_x, header.srow_y, header.srow_z]
+        );
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn analyze_round_trip() {
+        let path = std::env::temp_dir().join("nirust_analyze_test_a75.img");
+        let header = _header();
+        let (bytes, expected) = _data();
+        // radiological storage with an origin that fits the SPM originator
+        let affine = arr2(&[
+            [-2., 0., 0., 4.],
+            [0., 2.5, 0., -5.],
+            [0., 0., 3., -6.],
+            [0., 0., 0., 1.],
+        ]);
+        write_pair(
+            &path,
+            &header,
+            &[4, 3, 2],
+            &bytes,
+            ImageFormat::Analyze,
+            &affine,
+        )
+        .unwrap();
+        let (read, data, format) = read_pair(&path).unwrap();
+
+        assert_eq!(format, ImageFormat::Analyze);
+        assert_eq!(read.dim, header.dim);
+        assert_eq!(&read.pixdim[1..4], &header.pixdim[1..4]);
+        assert_eq!((read.datatype, read.bitpix), (4, 16));
+        // Analyze headers only store the SPM scale factor
+        assert_eq!((read.scl_slope, read.scl_inter), (0.5, 0.));
+        assert_eq!(read.qform_code, 0);
+        assert_eq!(get_affine(&read), affine);
+        assert_eq!(data, expected);
+    }
+}
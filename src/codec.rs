@@ -0,0 +1,234 @@
+//! The `nirust::codec` module converts voxel values between their on-disk
+//! byte representation and `f32`, and reads and writes (possibly compressed)
+//! files, for the file formats that are not handled by the `nifti` crate.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ndarray::prelude::*;
+use nifti::NiftiHeader;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::error::{NirustError, Result};
+use crate::image::Dtype;
+
+/// Decode voxel values stored with the given datatype and byte order.
+pub(crate) fn decode(
+    bytes: &[u8],
+    dtype: Dtype,
+    big_endian: bool,
+) -> Result<Vec<f32>> {
+    let n_bytes = dtype.bitpix() as usize / 8;
+    if !bytes.len().is_multiple_of(n_bytes) {
+        return Err(NirustError::Shape(format!(
+            "{} bytes of image data are not a multiple of the {} bytes of \
+            a {} voxel",
+            bytes.len(),
+            n_bytes,
+            dtype
+        )));
+    }
+    macro_rules! decode_as {
+        ($ty:ty) => {
+            bytes
+                .chunks_exact(n_bytes)
+                .map(|chunk| {
+                    let chunk = chunk.try_into().unwrap();
+                    if big_endian {
+                        <$ty>::from_be_bytes(chunk) as f32
+                    } else {
+                        <$ty>::from_le_bytes(chunk) as f32
+                    }
+                })
+                .collect()
+        };
+    }
+    let values = match dtype {
+        Dtype::Uint8 => decode_as!(u8),
+        Dtype::Int8 => decode_as!(i8),
+        Dtype::Uint16 => decode_as!(u16),
+        Dtype::Int16 => decode_as!(i16),
+        Dtype::Uint32 => decode_as!(u32),
+        Dtype::Int32 => decode_as!(i32),
+        Dtype::Uint64 => decode_as!(u64),
+        Dtype::Int64 => decode_as!(i64),
+        Dtype::Float32 => decode_as!(f32),
+        Dtype::Float64 => decode_as!(f64),
+    };
+    Ok(values)
+}
+
+/// Encode voxel values with the given datatype and byte order. Values are
+/// rounded (and saturated) when stored as integers.
+pub(crate) fn encode<I>(values: I, dtype: Dtype, big_endian: bool) -> Vec<u8>
+where
+    I: Iterator<Item = f64>,
+{
+    let mut bytes = Vec::new();
+    macro_rules! encode_as {
+        ($ty:ty, $convert:expr) => {
+            for v in values {
+                let v: $ty = $convert(v);
+                if big_endian {
+                    bytes.extend_from_slice(&v.to_be_bytes());
+                } else {
+                    bytes.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        };
+    }
+    match dtype {
+        Dtype::Uint8 => encode_as!(u8, |v: f64| v.round() as u8),
+        Dtype::Int8 => encode_as!(i8, |v: f64| v.round() as i8),
+        Dtype::Uint16 => encode_as!(u16, |v: f64| v.round() as u16),
+        Dtype::Int16 => encode_as!(i16, |v: f64| v.round() as i16),
+        Dtype::Uint32 => encode_as!(u32, |v: f64| v.round() as u32),
+        Dtype::Int32 => encode_as!(i32, |v: f64| v.round() as i32),
+        Dtype::Uint64 => encode_as!(u64, |v: f64| v.round() as u64),
+        Dtype::Int64 => encode_as!(i64, |v: f64| v.round() as i64),
+        Dtype::Float32 => encode_as!(f32, |v: f64| v as f32),
+        Dtype::Float64 => encode_as!(f64, |v: f64| v),
+    }
+    bytes
+}
+
+/// Whether a path points to a gzip-compressed file, judging by its extension
+/// (`.gz`, or `.mgz` for compressed MGH volumes).
+pub(crate) fn is_gzipped(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "gz" || ext == "mgz")
+}
+
+/// Read the whole content of a (possibly gzip-compressed) file.
+pub(crate) fn read_bytes(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let file = File::open(path)?;
+    if is_gzipped(path) {
+        MultiGzDecoder::new(BufReader::new(file)).read_to_end(&mut bytes)?;
+    } else {
+        BufReader::new(file).read_to_end(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// Read the first `n` bytes of a (possibly gzip-compressed) file, or fewer if
+/// the file is shorter.
+pub(crate) fn read_head(path: &Path, n: usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(n);
+    let file = File::open(path)?;
+    if is_gzipped(path) {
+        MultiGzDecoder::new(file)
+            .take(n as u64)
+            .read_to_end(&mut bytes)?;
+    } else {
+        file.take(n as u64).read_to_end(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// Write bytes to a file, compressing them with gzip if the path ends with
+/// `.gz` or `.mgz`.
+pub(crate) fn write_bytes(path: &Path, bytes: &[u8]) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    if is_gzipped(path) {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(bytes)?;
+        encoder.finish()?.flush()?;
+    } else {
+        let mut file = file;
+        file.write_all(bytes)?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+/// A header parsed by one of the readers of this crate, together with the
+/// information needed to decode the image data that follows it.
+pub(crate) struct ParsedHeader {
+    /// The header converted to a NIfTI-1 header.
+    pub header: NiftiHeader,
+    /// Shape of the image data, which takes precedence over `header.dim`.
+    pub shape: Vec<usize>,
+    pub dtype: Dtype,
+    pub big_endian: bool,
+    /// Offset of the image data in bytes.
+    pub vox_offset: usize,
+}
+
+impl ParsedHeader {
+    /// Decode the image data from the bytes of the file starting at
+    /// `vox_offset`. The values are returned as they are stored on disk.
+    pub fn decode_data(&self, bytes: &[u8]) -> Result<Array<f32, IxDyn>> {
+        let n_voxels: usize = self.shape.iter().product();
+        let n_bytes = n_voxels * self.dtype.bitpix() as usize / 8;
+        let end = self.vox_offset + n_bytes;
+        if bytes.len() < end {
+            return Err(NirustError::Format(format!(
+                "expected {} bytes of image data, found {}",
+                n_bytes,
+                bytes.len().saturating_sub(self.vox_offset)
+            )));
+        }
+        let values =
+            decode(&bytes[self.vox_offset..end], self.dtype, self.big_endian)?;
+        // NIfTI stores the first dimension fastest, i.e. in Fortran order
+        Array::from_shape_vec(IxDyn(&self.shape).f(), values)
+            .map_err(|e| NirustError::Shape(e.to_string()))
+    }
+}
+
+/// Access to the fields of a binary header in either byte order.
+pub(crate) struct HeaderFields<'a> {
+    pub bytes: &'a [u8],
+    pub big_endian: bool,
+}
+
+impl HeaderFields<'_> {
+    fn array<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let mut array: [u8; N] =
+            self.bytes[offset..offset + N].try_into().unwrap();
+        if self.big_endian {
+            array.reverse();
+        }
+        array
+    }
+
+    pub fn i16(&self, offset: usize) -> i16 {
+        i16::from_le_bytes(self.array(offset))
+    }
+
+    pub fn i32(&self, offset: usize) -> i32 {
+        i32::from_le_bytes(self.array(offset))
+    }
+
+    pub fn i64(&self, offset: usize) -> i64 {
+        i64::from_le_bytes(self.array(offset))
+    }
+
+    pub fn f32(&self, offset: usize) -> f32 {
+        f32::from_le_bytes(self.array(offset))
+    }
+
+    pub fn f64(&self, offset: usize) -> f64 {
+        f64::from_le_bytes(self.array(offset))
+    }
+}
+
+/// Little endian binary header under construction.
+pub(crate) struct HeaderBuilder {
+    pub bytes: Vec<u8>,
+}
+
+impl HeaderBuilder {
+    pub fn new(size: usize) -> Self {
+        HeaderBuilder {
+            bytes: vec![0u8; size],
+        }
+    }
+
+    pub fn put(&mut self, offset: usize, value: &[u8]) {
+        self.bytes[offset..offset + value.len()].copy_from_slice(value);
+    }
+}
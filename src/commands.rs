@@ -1,24 +1,45 @@
 //! All CLI commands are implemented in the `nirust::commands` module.
 //! Specifically, each command is implemented as a struct with fields
 //! corresponding to the arguments. Each command struct needs to implement the
-//! `execute` method.
+//! `execute` method, which returns a `NirustError` if the command fails.
 
 use clap::{Args, Parser, Subcommand};
 
-use log::info;
+use log::{info, warn};
 use ndarray::prelude::*;
+use ndarray_linalg::solve::Inverse;
 use std::path::Path;
+use std::str::FromStr;
 
 use crate::{
-    image::{get_affine, load_img, resample_3d_nifti, save_img},
-    masking::{mask_hemi, parcellate},
+    error::{NirustError, Result},
+    image::{
+        load_img, load_img_with_scaling, resample_img, resample_to_img,
+        resample_to_img_with_field, resample_to_voxel_size, save_img,
+        save_img_with_dtype, Dtype, DtypePolicy, Interpolation, Scaling,
+    },
+    info::ImageInfo,
+    masking::{
+        hemisphere_labels, mask_hemi, mask_hemi_with_labels, parcellate,
+        read_hemisphere_table,
+    },
+    orientation::{axis_codes, reorient},
+    registration::{
+        realign, register, CostFunction, RealignReference, RegistrationModel,
+    },
+    slice_timing::{slice_time_correct, SliceInterpolation, SliceTiming},
     statistics::voxelwise_tsnr,
+    templates::{resample_to_template, TemplateGrid},
+    transforms::{
+        apply_transform, read_affine, read_displacement_field, read_transform,
+        write_affine, TransformFormat,
+    },
 };
 
 // For every command, the trait ExecutableCommand should be implemented by
 // writing an associated method 'execute'
 pub trait ExecutableCommand {
-    fn execute(&self);
+    fn execute(&self) -> Result<()>;
 }
 
 // Define the Argument Parser
@@ -48,9 +69,64 @@ pub enum ActionType {
     /// Parcellate a 3D or 4D NIfTI image.
     Parcellate(ParcellateCommand),
 
-    /// Resample a 3D NIfTI image to another 3D or 4D reference image
-    /// using nearest neighbour interpolation.
+    /// Resample a 3D or 4D NIfTI image to another 3D or 4D reference image
+    /// using nearest neighbour, trilinear or cubic interpolation.
     ResampleToImage(ResampleToImageCommand),
+
+    /// Print the header, affine and intensity range of an image.
+    Info(InfoCommand),
+
+    /// Resample a 3D or 4D NIfTI image to a voxel size, or to a grid given
+    /// by an affine and a shape.
+    Resample(ResampleCommand),
+
+    /// Resample a 3D or 4D NIfTI image onto a reference image with a linear
+    /// transform from FSL, ANTs/ITK or AFNI.
+    ApplyTransform(ApplyTransformCommand),
+
+    /// Resample a 3D or 4D NIfTI image onto a reference image through a
+    /// displacement field from ANTs, FSL or AFNI, optionally followed by a
+    /// linear transform.
+    ApplyWarp(ApplyWarpCommand),
+
+    /// Estimate a rigid or affine transform between two 3D NIfTI images and
+    /// resample the input onto the reference.
+    Register(RegisterCommand),
+
+    /// Correct head motion by aligning every volume of a 4D NIfTI image to a
+    /// reference volume, and write the motion parameters.
+    Realign(RealignCommand),
+
+    /// Correct a 4D NIfTI image for the acquisition times of its slices.
+    SliceTime(SliceTimeCommand),
+
+    /// Reorient a NIfTI image to the closest canonical (RAS) or another
+    /// orientation by flipping and permuting its axes, without interpolation.
+    Reorient(ReorientCommand),
+}
+
+// Arguments shared by the commands that write an image.
+#[derive(Debug, Args)]
+pub struct OutputDtypeArgs {
+    /// Datatype of the output image: 'keep' the datatype of the input, 'auto'
+    /// to use the smallest lossless integer type for label images, or a
+    /// datatype such as 'uint8', 'int16' or 'float32'.
+    #[arg(long, default_value = "keep")]
+    pub output_dtype: DtypePolicy,
+}
+
+// Arguments shared by the commands that resample an image onto another grid.
+#[derive(Debug, Args)]
+pub struct ResamplingArgs {
+    /// Interpolation: 'nearest' for parcellations and other label images,
+    /// 'trilinear' for continuous images, or 'cubic' for B-spline
+    /// interpolation of anatomical images (nilearn's 'continuous').
+    #[arg(long, default_value = "nearest")]
+    pub interpolation: Interpolation,
+    /// Value of voxels outside of the field of view of the input, e.g. '0'
+    /// or 'nan'.
+    #[arg(long, default_value_t = 0.)]
+    pub fill_value: f32,
 }
 
 #[derive(Debug, Args)]
@@ -61,25 +137,109 @@ pub struct MaskHemiCommand {
     pub output_nifti: String,
     /// Mask 'left' or 'right' hemisphere.
     pub side: String,
+    /// World x coordinate (mm) of the midline, which is 0 in MNI space.
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    pub midline_x: f32,
+    /// Keep voxels within half a voxel of the midline, instead of masking
+    /// them with either hemisphere.
+    #[arg(long)]
+    pub keep_midline: bool,
+    /// Hemisphere label image (1 for left, 2 for right) in any space aligned
+    /// with the input, used instead of the world x coordinates, e.g. for
+    /// images in native space.
+    #[arg(long, conflicts_with_all = ["midline_x", "keep_midline"])]
+    pub hemispheres: Option<String>,
+    /// Parcellation whose labels are assigned to hemispheres by
+    /// --label-table, used like --hemispheres.
+    #[arg(
+        long,
+        conflicts_with_all = ["hemispheres", "midline_x", "keep_midline"],
+        requires = "label_table"
+    )]
+    pub parcellation: Option<String>,
+    /// Table with one label of --parcellation and its hemisphere ('left' or
+    /// 'right') per line.
+    #[arg(long, requires = "parcellation")]
+    pub label_table: Option<String>,
+    /// Value of masked voxels, e.g. 'nan' or '0'. Integer inputs are stored
+    /// as float32 if it is not finite and the output datatype is 'keep'.
+    #[arg(long, default_value_t = f32::NAN)]
+    pub fill_value: f32,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
 }
 
 impl ExecutableCommand for MaskHemiCommand {
-    fn execute(&self) {
+    fn execute(&self) -> Result<()> {
         info!("Running mask-hemi command...");
         match self.side.as_str() {
             "left" => info!("Masking left hemisphere..."),
             "right" => info!("Masking right hemisphere ..."),
-            _ => panic!("Error: 'side' parameter can be 'left' or 'right'!"),
+            _ => {
+                return Err(NirustError::Argument(format!(
+                    "'side' parameter can be 'left' or 'right', got '{}'",
+                    self.side
+                )))
+            }
+        }
+
+        if let DtypePolicy::Fixed(dtype) = self.output.output_dtype {
+            if dtype.is_integer() && !self.fill_value.is_finite() {
+                return Err(NirustError::Argument(format!(
+                    "fill value {} cannot be stored as {}, use a finite \
+                    --fill-value or a float --output-dtype",
+                    self.fill_value, dtype
+                )));
+            }
         }
 
-        // function that loads image and returns header and ndarray
-        let (header, mut image_data) = load_img(Path::new(&self.input_nifti));
+        // function that loads image with header and ndarray
+        let mut image = load_img(Path::new(&self.input_nifti))?;
 
-        // function that does masking and returns header and ndarray
-        mask_hemi(&header, &mut image_data, &self.side);
+        // integer datatypes cannot store a non-finite fill value
+        let output_dtype = match (self.output.output_dtype, image.dtype()) {
+            (DtypePolicy::Keep, Some(dtype))
+                if dtype.is_integer() && !self.fill_value.is_finite() =>
+            {
+                warn!(
+                    "Storing {} input as float32 to keep the fill value {}",
+                    dtype, self.fill_value
+                );
+                DtypePolicy::Fixed(Dtype::Float32)
+            }
+            (policy, _) => policy,
+        };
+
+        // function that masks the image data in place
+        let hemispheres = match (&self.hemispheres, &self.parcellation) {
+            (Some(hemispheres), _) => Some(load_img(Path::new(hemispheres))?),
+            (None, Some(parcellation)) => {
+                let parcellation = load_img(Path::new(parcellation))?;
+                // --label-table is required by --parcellation
+                let table = self.label_table.as_ref().unwrap();
+                let (left, right) = read_hemisphere_table(Path::new(table))?;
+                Some(hemisphere_labels(&parcellation, &left, &right)?)
+            }
+            (None, None) => None,
+        };
+        match hemispheres {
+            Some(hemispheres) => mask_hemi_with_labels(
+                &mut image,
+                &self.side,
+                &hemispheres,
+                self.fill_value,
+            )?,
+            None => mask_hemi(
+                &mut image,
+                &self.side,
+                self.midline_x,
+                self.keep_midline,
+                self.fill_value,
+            )?,
+        }
 
         // function that saves header and ndarray to a nifti
-        save_img(Path::new(&self.output_nifti), &header, image_data);
+        save_img_with_dtype(Path::new(&self.output_nifti), &image, output_dtype)
     }
 }
 
@@ -89,14 +249,22 @@ pub struct TemporalSNRCommand {
     pub input_nifti: String,
     /// Path to store the voxel-wise tSNR as a NIfTI image.
     pub output_nifti: String,
+    /// Datatype of the tSNR image: 'keep' to store it as float32, or a
+    /// datatype such as 'float64'.
+    #[arg(long, default_value = "keep")]
+    pub output_dtype: DtypePolicy,
 }
 
 impl ExecutableCommand for TemporalSNRCommand {
-    fn execute(&self) {
-        let (header, image_data) = load_img(Path::new(&self.input_nifti));
-        let image_data = voxelwise_tsnr(image_data);
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let tsnr = voxelwise_tsnr(&image)?;
         info!("Saving tSNR NIfTI image at {}", self.output_nifti);
-        save_img(Path::new(&self.output_nifti), &header, image_data);
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &tsnr,
+            self.output_dtype,
+        )
     }
 }
 
@@ -111,72 +279,576 @@ pub struct ParcellateCommand {
 }
 
 impl ExecutableCommand for ParcellateCommand {
-    fn execute(&self) {
+    fn execute(&self) -> Result<()> {
         info!("Running parcellate command...");
 
-        let (header_img, image_data) = load_img(Path::new(&self.input_nifti));
-
-        let (header_parc, parc_data) =
-            load_img(Path::new(&self.parcellation_nifti));
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let parcellation = load_img(Path::new(&self.parcellation_nifti))?;
 
-        let x = parc_data.shape()[0];
-        let y = parc_data.shape()[1];
-        let z = parc_data.shape()[2];
-        let shape = (x, y, z);
-        let parc_data_shaped: Array<f32, Ix3> =
-            parc_data.into_shape(shape).unwrap();
-
-        let parcellated = parcellate(
-            &image_data,
-            &header_img,
-            &parc_data_shaped,
-            &header_parc,
-        );
+        let parcellated = parcellate(&image, &parcellation)?;
         println!("{:?}", parcellated);
+        Ok(())
     }
 }
 
 #[derive(Debug, Args)]
 pub struct ResampleToImageCommand {
-    /// 3D NIfTI to resample.
+    /// 3D or 4D NIfTI to resample, 4D images are resampled volume by volume.
     pub input_nifti: String,
-    /// Reference NIfTI image as resampling target.
+    /// Reference NIfTI image as resampling target, or the name of a built-in
+    /// template grid such as 'MNI152NLin2009cAsym_2mm' or
+    /// 'MNI152NLin6Asym_1mm' (1, 2 and 3 mm grids are available).
     pub reference_nifti: String,
-    /// Output path.    
+    /// Output path.
     pub output_nifti: String,
+    #[command(flatten)]
+    pub resampling: ResamplingArgs,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
 }
 
 impl ExecutableCommand for ResampleToImageCommand {
-    fn execute(&self) {
+    fn execute(&self) -> Result<()> {
         let input_nifti = Path::new(&self.input_nifti);
         let ref_nifti = Path::new(&self.reference_nifti);
-        let (header, image_data) = load_img(input_nifti);
-        let (ref_header, ref_image) = load_img(ref_nifti);
-
-        let i = image_data.shape()[0];
-        let j = image_data.shape()[1];
-        let k = image_data.shape()[2];
-
-        let image_data_shaped: Array<f32, Ix3> =
-            image_data.into_shape((i, j, k)).unwrap();
-
-        let target_shape = ref_image.shape();
-        let x = target_shape[0];
-        let y = target_shape[1];
-        let z = target_shape[2];
-
-        let image_affine = get_affine(&header);
-        let ref_affine = get_affine(&ref_header);
-        let data_resampled = resample_3d_nifti(
-            &image_data_shaped,
-            &image_affine,
-            &ref_affine,
-            (x, y, z),
-        );
-        save_img(
+        let image = load_img(input_nifti)?;
+
+        // a template name is only used if there is no file of that name
+        let template = TemplateGrid::from_name(&self.reference_nifti);
+        let resampled = match template {
+            Some(grid) if !ref_nifti.exists() => {
+                info!("Resampling to template grid {}", grid.name);
+                resample_to_template(
+                    &image,
+                    grid.name,
+                    self.resampling.interpolation,
+                    self.resampling.fill_value,
+                )?
+            }
+            _ => resample_to_img(
+                &image,
+                &load_img(ref_nifti)?,
+                self.resampling.interpolation,
+                self.resampling.fill_value,
+            )?,
+        };
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &resampled,
+            self.output.output_dtype,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InfoCommand {
+    /// Image to describe.
+    pub input_nifti: String,
+    /// Print the information as JSON instead of human-readable text.
+    #[arg(long)]
+    pub json: bool,
+}
+
+impl ExecutableCommand for InfoCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let info = ImageInfo::new(&image);
+        if self.json {
+            println!("{:#}", info.to_json());
+        } else {
+            println!("{}", info);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ResampleCommand {
+    /// 3D or 4D NIfTI to resample.
+    pub input_nifti: String,
+    /// Output path.
+    pub output_nifti: String,
+    /// Voxel size of the output, either one value for isotropic voxels
+    /// (e.g. '2') or three comma-separated values (e.g. '2,2,3'). The field
+    /// of view is chosen to cover the whole input.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with = "affine",
+        required_unless_present = "affine"
+    )]
+    pub voxel_size: Vec<f32>,
+    /// Text file with the 4x4 affine of the output grid, one row per line
+    /// (e.g. written with numpy.savetxt). Requires --shape.
+    #[arg(long, requires = "shape")]
+    pub affine: Option<String>,
+    /// Spatial shape of the output grid as three comma-separated values,
+    /// e.g. '91,109,91'.
+    #[arg(long, value_delimiter = ',', requires = "affine")]
+    pub shape: Option<Vec<usize>>,
+    #[command(flatten)]
+    pub resampling: ResamplingArgs,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for ResampleCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+
+        let resampled = match (&self.affine, &self.shape) {
+            (Some(affine_path), Some(shape)) => {
+                let affine = read_affine(Path::new(affine_path))?;
+                let shape = match *shape.as_slice() {
+                    [x, y, z] => (x, y, z),
+                    _ => {
+                        return Err(NirustError::Argument(format!(
+                            "--shape needs three values, got {:?}",
+                            shape
+                        )))
+                    }
+                };
+                resample_img(
+                    &image,
+                    &affine,
+                    shape,
+                    self.resampling.interpolation,
+                    self.resampling.fill_value,
+                )?
+            }
+            _ => {
+                let voxel_size = match *self.voxel_size.as_slice() {
+                    [size] => (size, size, size),
+                    [x, y, z] => (x, y, z),
+                    _ => {
+                        return Err(NirustError::Argument(format!(
+                            "--voxel-size needs one or three values, got {:?}",
+                            self.voxel_size
+                        )))
+                    }
+                };
+                resample_to_voxel_size(
+                    &image,
+                    voxel_size,
+                    self.resampling.interpolation,
+                    self.resampling.fill_value,
+                )?
+            }
+        };
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &resampled,
+            self.output.output_dtype,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ApplyTransformCommand {
+    /// 3D or 4D NIfTI to resample (the moving image of the registration).
+    pub input_nifti: String,
+    /// Reference NIfTI image the input was registered to, whose grid is
+    /// matched.
+    pub reference_nifti: String,
+    /// Linear transform: an FSL FLIRT matrix (.mat), an ITK/ANTs transform
+    /// (.txt, .tfm or binary .mat) or an AFNI matrix (.1D).
+    pub transform: String,
+    /// Output path.
+    pub output_nifti: String,
+    /// Format of the transform: 'fsl', 'itk' (or 'ants'), 'afni' or 'ras'
+    /// for a 4x4 affine mapping RAS world coordinates of the reference to
+    /// those of the input. Guessed from the file if not given, except for
+    /// 4x4 affines in .txt files, which require '--format ras'.
+    #[arg(long)]
+    pub format: Option<TransformFormat>,
+    /// Apply the inverse of the transform, i.e. the transform was estimated
+    /// with the reference as moving image.
+    #[arg(long)]
+    pub invert: bool,
+    #[command(flatten)]
+    pub resampling: ResamplingArgs,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for ApplyTransformCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let reference = load_img(Path::new(&self.reference_nifti))?;
+        let transform_path = Path::new(&self.transform);
+        let format = match self.format {
+            Some(format) => format,
+            None => TransformFormat::from_path(transform_path)?,
+        };
+        info!("Reading {:?} transform {:?}", format, transform_path);
+
+        // an inverted transform was estimated with the roles of the images
+        // swapped, which matters for FSL matrices
+        let transform = if self.invert {
+            read_transform(transform_path, format, &reference, &image)?
+                .inv()
+                .map_err(|e| {
+                    NirustError::Affine(format!(
+                        "transform is singular ({})",
+                        e
+                    ))
+                })?
+        } else {
+            read_transform(transform_path, format, &image, &reference)?
+        };
+
+        let resampled = apply_transform(
+            &image,
+            &reference,
+            &transform,
+            self.resampling.interpolation,
+            self.resampling.fill_value,
+        )?;
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &resampled,
+            self.output.output_dtype,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ApplyWarpCommand {
+    /// 3D or 4D NIfTI to resample (the moving image of the registration).
+    pub input_nifti: String,
+    /// Reference NIfTI image the input was registered to, whose grid is
+    /// matched.
+    pub reference_nifti: String,
+    /// Displacement field defined for the reference image, e.g. an ANTs
+    /// warp (5D NIfTI) or an FSL warp converted to relative displacements.
+    pub warp: String,
+    /// Output path.
+    pub output_nifti: String,
+    /// Format of the displacement field: 'itk' (or 'ants'), 'fsl', 'afni' or
+    /// 'ras'. Guessed from the dimensions of the field if not given: 5D
+    /// fields are read as ITK and 4D fields as FSL fields.
+    #[arg(long)]
+    pub warp_format: Option<TransformFormat>,
+    /// Linear transform applied after the displacement field, e.g. the
+    /// affine of an ANTs registration or the FLIRT pre-matrix of an FSL warp.
+    #[arg(long)]
+    pub transform: Option<String>,
+    /// Format of the linear transform, see apply-transform.
+    #[arg(long, requires = "transform")]
+    pub format: Option<TransformFormat>,
+    #[command(flatten)]
+    pub resampling: ResamplingArgs,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for ApplyWarpCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let reference = load_img(Path::new(&self.reference_nifti))?;
+        let field =
+            read_displacement_field(Path::new(&self.warp), self.warp_format)?;
+
+        let transform = match &self.transform {
+            Some(transform) => {
+                let transform_path = Path::new(transform);
+                let format = match self.format {
+                    Some(format) => format,
+                    None => TransformFormat::from_path(transform_path)?,
+                };
+                info!("Reading {:?} transform {:?}", format, transform_path);
+                Some(read_transform(
+                    transform_path,
+                    format,
+                    &image,
+                    &reference,
+                )?)
+            }
+            None => None,
+        };
+
+        let resampled = resample_to_img_with_field(
+            &image,
+            &reference,
+            &field,
+            transform.as_ref(),
+            self.resampling.interpolation,
+            self.resampling.fill_value,
+        )?;
+        save_img_with_dtype(
             Path::new(&self.output_nifti),
-            &ref_header,
-            data_resampled.into_dyn(),
+            &resampled,
+            self.output.output_dtype,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct RegisterCommand {
+    /// 3D NIfTI to align (the moving image), e.g. a mean EPI.
+    pub input_nifti: String,
+    /// 3D NIfTI to align the input to, e.g. a T1.
+    pub reference_nifti: String,
+    /// Path to store the input resampled onto the reference.
+    pub output_nifti: String,
+    /// Path to store the estimated transform as a 4x4 affine mapping RAS
+    /// world coordinates of the reference to those of the input, which can
+    /// be applied with 'apply-transform --format ras'.
+    pub output_matrix: String,
+    /// Degrees of freedom: '6' (or 'rigid') or '12' (or 'affine').
+    #[arg(long, default_value = "6")]
+    pub dof: RegistrationModel,
+    /// Cost function: 'mi' (normalized mutual information) for images with
+    /// different contrasts, or 'nc' (normalized correlation) for images with
+    /// the same contrast.
+    #[arg(long, default_value = "mi")]
+    pub cost: CostFunction,
+    /// Voxel sizes (mm) of the resolution pyramid, from coarse to fine.
+    #[arg(long, value_delimiter = ',', default_value = "8,4,2")]
+    pub levels: Vec<f32>,
+    /// Interpolation of the output image: 'nearest', 'trilinear' or 'cubic'.
+    #[arg(long, default_value = "trilinear")]
+    pub interpolation: Interpolation,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for RegisterCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let reference = load_img(Path::new(&self.reference_nifti))?;
+
+        let transform =
+            register(&image, &reference, self.dof, self.cost, &self.levels)?;
+        info!("Estimated transform:\n{}", transform);
+        write_affine(Path::new(&self.output_matrix), &transform)?;
+
+        let resampled = apply_transform(
+            &image,
+            &reference,
+            &transform,
+            self.interpolation,
+            0.,
+        )?;
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &resampled,
+            self.output.output_dtype,
+        )
+    }
+}
+
+/// Layout of a motion parameter file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionOrder {
+    /// Rotations (radians) followed by translations (mm) without a header,
+    /// as in the .par files of FSL MCFLIRT.
+    Fsl,
+    /// Translations (mm) followed by rotations (radians) without a header,
+    /// as in the rp_*.txt files of SPM.
+    Spm,
+    /// Translations followed by rotations as a tab-separated table with the
+    /// column names of the fMRIPrep confounds (e.g. 'trans_x', 'rot_z').
+    Bids,
+}
+
+impl FromStr for MotionOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fsl" => Ok(MotionOrder::Fsl),
+            "spm" => Ok(MotionOrder::Spm),
+            "bids" => Ok(MotionOrder::Bids),
+            _ => Err(format!(
+                "unknown motion parameter order '{}', expected 'fsl', 'spm' \
+                or 'bids'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct RealignCommand {
+    /// 4D NIfTI to realign, e.g. a BOLD run.
+    pub input_nifti: String,
+    /// Path to store the realigned 4D NIfTI.
+    pub output_nifti: String,
+    /// Path to store the motion parameters, with one row per volume.
+    pub output_motion: String,
+    /// Volume to align to: 'first', 'mean' or the (zero-based) index of a
+    /// volume.
+    #[arg(long, default_value = "mean")]
+    pub reference: RealignReference,
+    /// Layout of the motion parameters: 'fsl' (rotations, then translations,
+    /// as MCFLIRT .par files), 'spm' (translations, then rotations, as SPM
+    /// rp_*.txt files) or 'bids' (a .tsv file with a header row).
+    #[arg(long, default_value = "fsl")]
+    pub order: MotionOrder,
+    /// Cost function: 'nc' (normalized correlation) or 'mi' (normalized
+    /// mutual information).
+    #[arg(long, default_value = "nc")]
+    pub cost: CostFunction,
+    /// Voxel sizes (mm) of the resolution pyramid, from coarse to fine.
+    #[arg(long, value_delimiter = ',', default_value = "8,4,2")]
+    pub levels: Vec<f32>,
+    /// Interpolation of the output image: 'nearest', 'trilinear' or 'cubic'.
+    #[arg(long, default_value = "trilinear")]
+    pub interpolation: Interpolation,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for RealignCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let (realigned, motion) = realign(
+            &image,
+            self.reference,
+            self.cost,
+            &self.levels,
+            self.interpolation,
+        )?;
+        _write_motion(Path::new(&self.output_motion), &motion, self.order)?;
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &realigned,
+            self.output.output_dtype,
+        )
+    }
+}
+
+/// Write motion parameters (translations, then rotations) in the layout of
+/// FSL, SPM or as a .tsv file with a header row.
+fn _write_motion(
+    path: &Path,
+    motion: &Array2<f32>,
+    order: MotionOrder,
+) -> Result<()> {
+    let (columns, separator) = match order {
+        MotionOrder::Fsl => ([3, 4, 5, 0, 1, 2], "  "),
+        MotionOrder::Spm => ([0, 1, 2, 3, 4, 5], "  "),
+        MotionOrder::Bids => ([0, 1, 2, 3, 4, 5], "\t"),
+    };
+    // FSL and SPM files are plain matrices, as expected by their readers
+    let mut text = match order {
+        MotionOrder::Bids => {
+            "trans_x\ttrans_y\ttrans_z\trot_x\trot_y\trot_z\n".to_string()
+        }
+        MotionOrder::Fsl | MotionOrder::Spm => String::new(),
+    };
+    for row in motion.rows() {
+        let values: Vec<String> =
+            columns.iter().map(|&c| format!("{:.6}", row[c])).collect();
+        text += &(values.join(separator) + "\n");
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct SliceTimeCommand {
+    /// 4D NIfTI to correct, e.g. a BOLD run.
+    pub input_nifti: String,
+    /// Path to store the corrected 4D NIfTI.
+    pub output_nifti: String,
+    /// BIDS JSON sidecar with the 'SliceTiming' of the image. If it is not
+    /// given, the timing is derived from the slice code and slice duration
+    /// in the NIfTI header.
+    #[arg(long)]
+    pub slice_timing: Option<String>,
+    /// Time (seconds) within the repetition time to which all slices are
+    /// interpolated, by default the middle of the slice acquisition times.
+    #[arg(long)]
+    pub reference_time: Option<f32>,
+    /// Interpolation of the time series: 'linear' or 'sinc' (FFT).
+    #[arg(long, default_value = "sinc")]
+    pub interpolation: SliceInterpolation,
+    #[command(flatten)]
+    pub output: OutputDtypeArgs,
+}
+
+impl ExecutableCommand for SliceTimeCommand {
+    fn execute(&self) -> Result<()> {
+        let image = load_img(Path::new(&self.input_nifti))?;
+        let timing = match &self.slice_timing {
+            Some(json) => SliceTiming::from_json(Path::new(json))?,
+            None => SliceTiming::from_header(&image)?,
+        };
+        info!("Slice times (s): {:?}", timing.times);
+
+        let corrected = slice_time_correct(
+            &image,
+            &timing,
+            self.reference_time,
+            self.interpolation,
+        )?;
+        save_img_with_dtype(
+            Path::new(&self.output_nifti),
+            &corrected,
+            self.output.output_dtype,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ReorientCommand {
+    /// NIfTI file to reorient.
+    pub input_nifti: String,
+    /// Path to store the reoriented NIfTI.
+    pub output_nifti: String,
+    /// Target axis codes, e.g. 'RAS' (the closest canonical orientation),
+    /// 'LPS' or 'LAS'.
+    #[arg(long, default_value = "RAS")]
+    pub orientation: String,
+}
+
+impl ExecutableCommand for ReorientCommand {
+    fn execute(&self) -> Result<()> {
+        // the values are only copied, so they are stored as they are
+        let image =
+            load_img_with_scaling(Path::new(&self.input_nifti), Scaling::Raw)?;
+        let reoriented = reorient(&image, &self.orientation)?;
+        info!(
+            "Reoriented image from {} to {}",
+            axis_codes(image.affine()),
+            axis_codes(reoriented.affine())
+        );
+        save_img(Path::new(&self.output_nifti), &reoriented)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fsl_motion_has_rotations_first_and_no_header() {
+        let path = std::env::temp_dir().join("nirust_commands_test_fsl.par");
+        // trans x, y, z then rot x, y, z, as returned by realign
+        let motion = arr2(&[
+            [1., 2., 3., 0.1, 0.2, 0.3],
+            [-1., -2., -3., -0.1, -0.2, -0.3],
+        ]);
+        _write_motion(&path, &motion, MotionOrder::Fsl).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+
+        // a header line would not parse as numbers
+        let rows: Vec<Vec<f32>> = text
+            .lines()
+            .map(|line| {
+                line.split_whitespace()
+                    .map(|v| v.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![0.1, 0.2, 0.3, 1., 2., 3.],
+                vec![-0.1, -0.2, -0.3, -1., -2., -3.],
+            ]
         );
     }
 }
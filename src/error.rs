@@ -0,0 +1,78 @@
+//! The `nirust::error` module defines `NirustError`, the error type returned
+//! by all fallible functions and commands of the crate, so that callers can
+//! recover from bad input instead of the program panicking.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Result type used throughout the crate.
+pub type Result<T> = std::result::Result<T, NirustError>;
+
+/// All errors that can occur when running nirust functions or commands.
+#[derive(Debug)]
+pub enum NirustError {
+    /// Reading or writing a file failed, e.g. because it does not exist.
+    Io(io::Error),
+    /// The `nifti` crate could not parse or write an image.
+    Nifti(nifti::NiftiError),
+    /// A file is not valid in the format it claims to have.
+    Format(String),
+    /// An array does not have the expected shape or dimensionality.
+    Shape(String),
+    /// An affine matrix cannot be used, e.g. because it is singular.
+    Affine(String),
+    /// A function or command received an invalid argument.
+    Argument(String),
+}
+
+impl NirustError {
+    /// Exit code used by the `nirust` binary when a command fails with this
+    /// error. Argument errors use 2, like the usage errors reported by clap.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            NirustError::Argument(_) => 2,
+            NirustError::Io(_) => 3,
+            NirustError::Nifti(_) | NirustError::Format(_) => 4,
+            NirustError::Shape(_) => 5,
+            NirustError::Affine(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for NirustError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NirustError::Io(e) => write!(f, "I/O error: {}", e),
+            NirustError::Nifti(e) => write!(f, "NIfTI error: {}", e),
+            NirustError::Format(msg) => write!(f, "Invalid file: {}", msg),
+            NirustError::Shape(msg) => write!(f, "Shape mismatch: {}", msg),
+            NirustError::Affine(msg) => write!(f, "Invalid affine: {}", msg),
+            NirustError::Argument(msg) => {
+                write!(f, "Invalid argument: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for NirustError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NirustError::Io(e) => Some(e),
+            NirustError::Nifti(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NirustError {
+    fn from(e: io::Error) -> Self {
+        NirustError::Io(e)
+    }
+}
+
+impl From<nifti::NiftiError> for NirustError {
+    fn from(e: nifti::NiftiError) -> Self {
+        NirustError::Nifti(e)
+    }
+}
@@ -4,88 +4,847 @@
 //! example resampling an image using `resample_3d_nifti`).
 
 use itertools::iproduct;
+use ndarray::parallel::prelude::*;
 use ndarray::prelude::*;
+use ndarray::{Data, Zip};
 use ndarray_linalg::solve::Inverse;
 use nifti::{
-    writer::WriterOptions, IntoNdArray, NiftiHeader, NiftiObject, NiftiVolume,
-    ReaderOptions,
+    writer::WriterOptions, InMemNiftiVolume, IntoNdArray, NiftiHeader,
+    NiftiObject, ReaderOptions,
 };
 
 use log::{info, warn};
+use std::fmt;
+use std::io;
 use std::path::Path;
+use std::str::FromStr;
 
-/// Load a 3D or 4D NIfTI image.
+use crate::analyze::{is_pair_path, read_pair, write_pair};
+use crate::codec::encode;
+use crate::error::{NirustError, Result};
+use crate::mgh::{is_mgh_path, mgh_dtype, read_mgh, write_mgh};
+use crate::nifti2::{is_nifti2, read_nifti2, write_nifti2, NIFTI1_MAX_DIM};
+
+/// A NIfTI image held in memory.
+///
+/// The image owns the header with the image metadata, the voxel-wise image
+/// data, and the affine resolved from the header with `select_affine`, so
+/// that functions operating on images do not need to pass headers and arrays
+/// around separately.
+///
+/// The `scl_slope` and `scl_inter` fields of the header always describe how
+/// to obtain the real-world values from the image data. For images loaded
+/// with `Scaling::Apply` (the default) and for new images they are therefore
+/// set to 1 and 0, see `intensity_scaling`.
+#[derive(Debug, Clone)]
+pub struct NiftiImage {
+    header: NiftiHeader,
+    data: Array<f32, IxDyn>,
+    affine: Array2<f32>,
+    affine_kind: AffineKind,
+    format: ImageFormat,
+}
+
+/// Versions of the NIfTI file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// NIfTI-1, with a 348-byte header and dimensions of up to 32767 voxels.
+    Nifti1,
+    /// NIfTI-2, with a 540-byte header and 64-bit dimensions.
+    Nifti2,
+    /// Analyze 7.5, the predecessor of NIfTI-1 without orientation
+    /// information, which can only be stored as a pair of header and image
+    /// files.
+    Analyze,
+    /// FreeSurfer MGH (`.mgh`) or its compressed variant MGZ (`.mgz`).
+    Mgh,
+}
+
+impl NiftiImage {
+    /// Create an image from a header and the corresponding image data.
+    ///
+    /// The dimensions in the header are updated to match the shape of the
+    /// data, and the affine is resolved following the nibabel conventions
+    /// (see `get_affine`).
+    pub fn new(header: NiftiHeader, data: Array<f32, IxDyn>) -> Result<Self> {
+        let header = _header_for_shape(&header, data.shape())?;
+        let (affine, affine_kind) = select_affine(&header);
+        Ok(NiftiImage {
+            header,
+            data,
+            affine,
+            affine_kind,
+            format: ImageFormat::Nifti1,
+        })
+    }
+
+    /// Create a new image with the same header and affine as this image, but
+    /// different image data.
+    ///
+    /// The header of the new image is derived from the header of this image:
+    /// its dimensions are set to the shape of `data`, intensity scaling is
+    /// removed since `data` are real-world values, and `cal_min` and
+    /// `cal_max` are set to the range of `data`, so that viewers use a
+    /// sensible display range. The datatype is kept and can be changed with
+    /// `set_dtype`. Use `new_img_like_keep_scaling` for data that are copied
+    /// from this image.
+    pub fn new_img_like(&self, data: Array<f32, IxDyn>) -> Result<Self> {
+        let mut header = _header_for_shape(&self.header, data.shape())?;
+        header.scl_slope = 1.;
+        header.scl_inter = 0.;
+        let (cal_min, cal_max) = _finite_range(&data).unwrap_or((0., 0.));
+        header.cal_min = cal_min as f32;
+        header.cal_max = cal_max as f32;
+        Ok(NiftiImage {
+            header,
+            data,
+            affine: self.affine.clone(),
+            affine_kind: self.affine_kind,
+            format: self.format,
+        })
+    }
+
+    /// Create a new image with the same header and affine as this image, for
+    /// data whose values are copied from this image, e.g. flipped or
+    /// permuted.
+    ///
+    /// Unlike `new_img_like`, the intensity scaling and `cal_min` and
+    /// `cal_max` are kept, since the data are still stored values if this
+    /// image was loaded with `Scaling::Raw`. Only the dimensions are set to
+    /// the shape of `data`.
+    pub fn new_img_like_keep_scaling(
+        &self,
+        data: Array<f32, IxDyn>,
+    ) -> Result<Self> {
+        Ok(NiftiImage {
+            header: _header_for_shape(&self.header, data.shape())?,
+            data,
+            affine: self.affine.clone(),
+            affine_kind: self.affine_kind,
+            format: self.format,
+        })
+    }
+
+    /// Header metadata of the image.
+    pub fn header(&self) -> &NiftiHeader {
+        &self.header
+    }
+
+    /// Mutable header metadata, for fields that do not depend on the shape
+    /// or affine of the image (which are set by `new_img_like` and
+    /// `set_affine`).
+    pub(crate) fn header_mut(&mut self) -> &mut NiftiHeader {
+        &mut self.header
+    }
+
+    /// Voxel-wise image data.
+    pub fn data(&self) -> &Array<f32, IxDyn> {
+        &self.data
+    }
+
+    /// Mutable voxel-wise image data. The shape of the data should not be
+    /// changed, use `new_img_like` instead.
+    pub fn data_mut(&mut self) -> &mut Array<f32, IxDyn> {
+        &mut self.data
+    }
+
+    /// Consume the image and return the voxel-wise image data.
+    pub fn into_data(self) -> Array<f32, IxDyn> {
+        self.data
+    }
+
+    /// Affine matrix mapping voxel coordinates to world coordinates.
+    pub fn affine(&self) -> &Array2<f32> {
+        &self.affine
+    }
+
+    /// Which of the affines described by the header is used.
+    pub fn affine_kind(&self) -> AffineKind {
+        self.affine_kind
+    }
+
+    /// Set the affine of the image, e.g. after resampling it to a new grid.
+    ///
+    /// Like nibabel, both the sform and the qform of the header are set to
+    /// the affine, keeping their codes or using 'aligned' (2) for the sform
+    /// if it was unknown. The voxel sizes in `pixdim` are updated as well.
+    /// The qform can only represent rotations, zooms and translations, so it
+    /// approximates affines with shears.
+    pub fn set_affine(&mut self, affine: &Array2<f32>) {
+        let row = |i: usize| {
+            [
+                affine[[i, 0]],
+                affine[[i, 1]],
+                affine[[i, 2]],
+                affine[[i, 3]],
+            ]
+        };
+        self.header.srow_x = row(0);
+        self.header.srow_y = row(1);
+        self.header.srow_z = row(2);
+        if self.header.sform_code == 0 {
+            self.header.sform_code = 2;
+        }
+        _set_qform(&mut self.header, affine);
+        let (affine, affine_kind) = select_affine(&self.header);
+        self.affine = affine;
+        self.affine_kind = affine_kind;
+    }
+
+    /// Intensity scaling `(scl_slope, scl_inter)` that still has to be applied
+    /// to the image data to obtain real-world values, or `None` if the data
+    /// already are real-world values.
+    pub fn intensity_scaling(&self) -> Option<(f32, f32)> {
+        _header_scaling(&self.header)
+    }
+
+    /// Datatype of the image on disk as given by the header, or `None` if it
+    /// is not supported by nirust.
+    pub fn dtype(&self) -> Option<Dtype> {
+        Dtype::from_code(self.header.datatype)
+    }
+
+    /// Set the datatype with which the image will be stored by `save_img`.
+    pub fn set_dtype(&mut self, dtype: Dtype) {
+        self.header.datatype = dtype.code();
+        self.header.bitpix = dtype.bitpix();
+    }
+
+    /// NIfTI version the image was loaded from, and with which it will be
+    /// stored by `save_img`.
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Set the NIfTI version with which the image will be stored. Images
+    /// with dimensions that exceed the NIfTI-1 limit are always stored as
+    /// NIfTI-2.
+    pub fn set_format(&mut self, format: ImageFormat) {
+        self.format = format;
+    }
+
+    /// Number of dimensions of the image data.
+    pub fn ndim(&self) -> usize {
+        self.data.ndim()
+    }
+
+    /// Shape of the first three (spatial) dimensions of the image.
+    pub fn spatial_shape(&self) -> (usize, usize, usize) {
+        let shape = self.data.shape();
+        let dim = |axis: usize| shape.get(axis).copied().unwrap_or(1);
+        (dim(0), dim(1), dim(2))
+    }
+
+    /// Number of volumes of the image, i.e. 1 for a 3D image and the size of
+    /// the fourth (and any higher) dimension otherwise.
+    pub fn n_volumes(&self) -> usize {
+        self.data.shape().iter().skip(3).product()
+    }
+
+    /// Voxel sizes along the three spatial dimensions as stored in `pixdim`.
+    pub fn voxel_sizes(&self) -> (f32, f32, f32) {
+        let pixdim = self.header.pixdim;
+        (pixdim[1].abs(), pixdim[2].abs(), pixdim[3].abs())
+    }
+
+    /// Repetition time in seconds, or `None` if the image has no time
+    /// dimension. The unit is taken from the `xyzt_units` field of the
+    /// header, with seconds assumed if it is not set.
+    pub fn tr(&self) -> Option<f32> {
+        if self.ndim() < 4 || self.header.pixdim[4] <= 0. {
+            return None;
+        }
+        let scale = match self.header.xyzt_units & 0x38 {
+            16 => 1e-3,
+            24 => 1e-6,
+            _ => 1.,
+        };
+        Some(self.header.pixdim[4] * scale)
+    }
+
+    /// View of the data of a single-volume image as a 3D array.
+    ///
+    /// Returns a `Shape` error if the image has more than one volume.
+    pub fn data_3d(&self) -> Result<ArrayView3<'_, f32>> {
+        if self.n_volumes() != 1 {
+            return Err(NirustError::Shape(format!(
+                "expected a 3D image, got an image with shape {:?}",
+                self.data.shape()
+            )));
+        }
+        let mut view = self.data.view();
+        while view.ndim() > 3 {
+            let last = Axis(view.ndim() - 1);
+            view = view.index_axis_move(last, 0);
+        }
+        while view.ndim() < 3 {
+            let last = Axis(view.ndim());
+            view = view.insert_axis(last);
+        }
+        view.into_dimensionality::<Ix3>()
+            .map_err(|e| NirustError::Shape(e.to_string()))
+    }
+}
+
+// Copy a header and set its dimensions to the given shape. Dimensions that
+// exceed the NIfTI-1 limit are clamped, they are only stored in NIfTI-2
+// files, which are written from the shape of the data.
+fn _header_for_shape(
+    header: &NiftiHeader,
+    shape: &[usize],
+) -> Result<NiftiHeader> {
+    if shape.is_empty() || shape.len() > 7 {
+        return Err(NirustError::Shape(format!(
+            "NIfTI images have 1 to 7 dimensions, got {}",
+            shape.len()
+        )));
+    }
+    let mut header = header.clone();
+    header.dim = [1; 8];
+    header.dim[0] = shape.len() as u16;
+    for (axis, &n) in shape.iter().enumerate() {
+        header.dim[axis + 1] = n.min(NIFTI1_MAX_DIM) as u16;
+    }
+    Ok(header)
+}
+
+// Scaling given by a header, following the NIfTI standard that a slope of 0
+// means that the data are not scaled.
+fn _header_scaling(header: &NiftiHeader) -> Option<(f32, f32)> {
+    let (slope, inter) = (header.scl_slope, header.scl_inter);
+    if slope == 0. || !slope.is_finite() || !inter.is_finite() {
+        return None;
+    }
+    if slope == 1. && inter == 0. {
+        return None;
+    }
+    Some((slope, inter))
+}
+
+/// How to handle the intensity scaling given by `scl_slope` and `scl_inter`
+/// when loading an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// Apply the scaling, so that the image data are real-world values.
+    Apply,
+    /// Return the values as they are stored on disk. The scaling is kept in
+    /// the header and can be obtained with `NiftiImage::intensity_scaling`.
+    Raw,
+}
+
+/// Load a 3D or 4D NIfTI-1, NIfTI-2, Analyze 7.5 or MGH image.
+///
+/// The function loads the image into memory and returns a `NiftiImage`
+/// containing the header with the image metadata, the voxel-wise image data
+/// and the affine. Pairs of header and image files can be loaded from either
+/// of the two files, and paths ending with `.mgh` or `.mgz` are loaded as
+/// FreeSurfer volumes with the vox2ras matrix as sform. The intensity
+/// scaling of the header is applied, use `load_img_with_scaling` to obtain
+/// the values stored on disk.
 ///
-/// The function loads the image into memory and returns a tuple containing
-/// the header with the image metadata, and an ndarray with the voxel-wise
-/// image data.
-/// 
 /// Parameters
 /// ----------
 /// path : Path to the NIfTI image
 ///
-pub fn load_img(path: &Path) -> (NiftiHeader, Array<f32, IxDyn>) {
+pub fn load_img(path: &Path) -> Result<NiftiImage> {
+    load_img_with_scaling(path, Scaling::Apply)
+}
+
+/// Load a 3D or 4D NIfTI image and handle intensity scaling as specified.
+///
+/// Parameters
+/// ----------
+/// path : Path to the NIfTI image
+///
+/// scaling : Whether to apply `scl_slope` and `scl_inter` to the data.
+///
+pub fn load_img_with_scaling(
+    path: &Path,
+    scaling: Scaling,
+) -> Result<NiftiImage> {
     info!("Reading NIfTI at {:?}", path);
-    let img = match ReaderOptions::new().read_file(path) {
-        Ok(img) => img,
-        Err(e) => panic!("Error: {}", e),
+    if !path.exists() {
+        return Err(NirustError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{:?} does not exist", path),
+        )));
+    }
+    let (mut header, mut image_data, format) = if is_mgh_path(path) {
+        let (header, image_data) = read_mgh(path)?;
+        (header, image_data, ImageFormat::Mgh)
+    } else if is_pair_path(path) {
+        read_pair(path)?
+    } else if is_nifti2(path)? {
+        info!("NIfTI-2 format detected");
+        let (header, image_data) = read_nifti2(path)?;
+        (header, image_data, ImageFormat::Nifti2)
+    } else {
+        let (header, image_data) = _read_nifti1(path)?;
+        (header, image_data, ImageFormat::Nifti1)
     };
+    info!("Dimensions detected: {:?}", image_data.ndim());
+
+    match (scaling, _header_scaling(&header)) {
+        (Scaling::Apply, Some((slope, inter))) => {
+            info!("Applying scl_slope {} and scl_inter {}", slope, inter);
+            image_data.mapv_inplace(|v| v * slope + inter);
+            header.scl_slope = 1.;
+            header.scl_inter = 0.;
+        }
+        (Scaling::Raw, Some((slope, inter))) => {
+            info!(
+                "Returning raw values, scl_slope {} and scl_inter {} are \
+                not applied",
+                slope, inter
+            );
+        }
+        (_, None) => {
+            header.scl_slope = 1.;
+            header.scl_inter = 0.;
+        }
+    }
+
+    let mut image = NiftiImage::new(header, image_data)?;
+    image.set_format(format);
+    Ok(image)
+}
+
+// Read a NIfTI-1 image with the `nifti` crate, returning the values as they
+// are stored on disk.
+fn _read_nifti1(path: &Path) -> Result<(NiftiHeader, Array<f32, IxDyn>)> {
+    let img = ReaderOptions::new().read_file(path)?;
     let header = img.header().clone();
-    let volume = img.volume();
-    let dims = volume.dim();
-    let n_dims = dims.len();
-    info!("Dimensions detected: {:?}", n_dims);
-    let image_data = img.into_volume().into_ndarray::<f32>().unwrap();
 
-    (header, image_data)
+    // decode the stored values without any scaling, so that it is always
+    // clear whether and how the data were scaled
+    let mut raw_header = header.clone();
+    raw_header.scl_slope = 1.;
+    raw_header.scl_inter = 0.;
+    let raw_data = img.into_volume().into_raw_data();
+    let image_data = InMemNiftiVolume::from_raw_data(&raw_header, raw_data)?
+        .into_ndarray::<f32>()?;
+
+    Ok((header, image_data))
 }
 
 /// Save a 3D or 4D NIfTI image to disk.
 ///
+/// The data are stored with the datatype of the image header (see
+/// `DtypePolicy::Keep`), use `save_img_with_dtype` to choose another one.
+///
 /// Parameters
 /// ----------
 /// path : Path and filename of the data to be saved.
 ///
-/// header : Header metadata for the NIfTI image to be stored.
+/// image : Image to be stored.
+///
+pub fn save_img(path: &Path, image: &NiftiImage) -> Result<()> {
+    save_img_with_dtype(path, image, DtypePolicy::Keep)
+}
+
+/// Save a 3D or 4D NIfTI image to disk with a given datatype policy.
 ///
-/// image_data : ndarray containing the voxel-wise image data.
+/// If the data are stored as integers but cannot be represented exactly by
+/// the chosen datatype, `scl_slope` and `scl_inter` are set such that the
+/// full range of the data is covered. Otherwise, they are set to 1 and 0.
+/// Images loaded with `Scaling::Raw` are stored as they are, together with
+/// their intensity scaling.
+/// Non-finite values cannot be stored as integers and are stored as 0.
 ///
-pub fn save_img(
+/// The image is stored as NIfTI-2 if its format is `ImageFormat::Nifti2` or
+/// if any dimension exceeds the NIfTI-1 limit of 32767 voxels, and as NIfTI-1
+/// otherwise. If the path ends with `.hdr` or `.img` (optionally followed by
+/// `.gz`), a pair of header and image files is written, with an Analyze 7.5
+/// header if the format of the image is `ImageFormat::Analyze`. If the path
+/// ends with `.mgh` or `.mgz`, a FreeSurfer volume is written with the
+/// affine of the image as vox2ras matrix. MGH volumes have no intensity
+/// scaling, so data that would need it are stored as float32.
+///
+/// Parameters
+/// ----------
+/// path : Path and filename of the data to be saved.
+///
+/// image : Image to be stored.
+///
+/// policy : How to choose the datatype used to store the data.
+///
+pub fn save_img_with_dtype(
     path: &Path,
-    header: &NiftiHeader,
-    image_data: Array<f32, IxDyn>,
-) {
+    image: &NiftiImage,
+    policy: DtypePolicy,
+) -> Result<()> {
     if path.exists() {
         warn!("{:?} exists, overwriting image!", path);
     }
-    info!("Saving image at {:?}", path);
-    match WriterOptions::new(path)
-        .reference_header(header)
-        .write_nifti(&image_data)
-    {
-        Ok(()) => {}
-        Err(e) => {
-            panic!("Error: {}", e)
+    let data = image.data();
+    let dtype = match policy {
+        DtypePolicy::Keep => image.dtype().unwrap_or(Dtype::Float32),
+        DtypePolicy::Fixed(dtype) => dtype,
+        DtypePolicy::SmallestInteger => {
+            _smallest_integer_dtype(data).unwrap_or(Dtype::Float32)
+        }
+    };
+    // the data are converted to stored values with `stored_scaling`, and
+    // `slope` and `inter` are written to the header
+    let (stored_scaling, (slope, inter)) = match image.intensity_scaling() {
+        Some((slope, inter)) => ((1., 0.), (slope as f64, inter as f64)),
+        None => {
+            let scaling = _scaling_for_dtype(data, dtype);
+            (scaling, scaling)
+        }
+    };
+    if dtype.is_integer() && data.iter().any(|v| !v.is_finite()) {
+        warn!("Storing non-finite values as 0 in {} image!", dtype);
+    }
+
+    let mut header = image.header().clone();
+    header.datatype = dtype.code();
+    header.bitpix = dtype.bitpix();
+    header.scl_slope = slope as f32;
+    header.scl_inter = inter as f32;
+
+    let (stored_slope, stored_inter) = stored_scaling;
+    if is_mgh_path(path) {
+        let dtype = if (slope, inter) == (1., 0.) {
+            mgh_dtype(dtype)
+        } else {
+            Dtype::Float32
+        };
+        let real_values = data.mapv(|v| {
+            (((v as f64 - stored_inter) / stored_slope) * slope + inter) as f32
+        });
+        return write_mgh(
+            path,
+            &real_values,
+            dtype,
+            image.affine(),
+            image.tr(),
+        );
+    }
+    let is_pair = is_pair_path(path);
+    let is_nifti2 = image.format() == ImageFormat::Nifti2
+        || data.shape().iter().any(|&n| n > NIFTI1_MAX_DIM);
+    if is_pair || is_nifti2 {
+        // NIfTI stores the first dimension fastest, i.e. in Fortran order
+        let data_t = data.t();
+        let values = data_t
+            .iter()
+            .map(|&v| (v as f64 - stored_inter) / stored_slope);
+        let bytes = encode(values, dtype, false);
+        if is_pair {
+            info!("Saving {} image pair at {:?}", dtype, path);
+            return write_pair(
+                path,
+                &header,
+                data.shape(),
+                &bytes,
+                image.format(),
+                image.affine(),
+            );
+        }
+        info!("Saving {} NIfTI-2 image at {:?}", dtype, path);
+        return write_nifti2(path, &header, data.shape(), &bytes);
+    }
+
+    info!("Saving {} image at {:?}", dtype, path);
+    header.magic = *b"n+1\0";
+    header.vox_offset = 352.;
+    let options = WriterOptions::new(path).reference_header(&header);
+    macro_rules! write_as {
+        ($ty:ty) => {
+            options.write_nifti(&data.mapv(|v| {
+                ((v as f64 - stored_inter) / stored_slope).round() as $ty
+            }))?
+        };
+    }
+    match dtype {
+        Dtype::Uint8 => write_as!(u8),
+        Dtype::Int8 => write_as!(i8),
+        Dtype::Uint16 => write_as!(u16),
+        Dtype::Int16 => write_as!(i16),
+        Dtype::Uint32 => write_as!(u32),
+        Dtype::Int32 => write_as!(i32),
+        Dtype::Uint64 => write_as!(u64),
+        Dtype::Int64 => write_as!(i64),
+        Dtype::Float32 => options.write_nifti(
+            &data.mapv(|v| ((v as f64 - stored_inter) / stored_slope) as f32),
+        )?,
+        Dtype::Float64 => options.write_nifti(
+            &data.mapv(|v| (v as f64 - stored_inter) / stored_slope),
+        )?,
+    }
+    Ok(())
+}
+
+/// Datatypes in which nirust can store voxel values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dtype {
+    Uint8,
+    Int8,
+    Uint16,
+    Int16,
+    Uint32,
+    Int32,
+    Uint64,
+    Int64,
+    Float32,
+    Float64,
+}
+
+impl Dtype {
+    const ALL: [Dtype; 10] = [
+        Dtype::Uint8,
+        Dtype::Int8,
+        Dtype::Uint16,
+        Dtype::Int16,
+        Dtype::Uint32,
+        Dtype::Int32,
+        Dtype::Uint64,
+        Dtype::Int64,
+        Dtype::Float32,
+        Dtype::Float64,
+    ];
+
+    /// The NIfTI `datatype` code of this datatype.
+    pub fn code(self) -> i16 {
+        match self {
+            Dtype::Uint8 => 2,
+            Dtype::Int16 => 4,
+            Dtype::Int32 => 8,
+            Dtype::Float32 => 16,
+            Dtype::Float64 => 64,
+            Dtype::Int8 => 256,
+            Dtype::Uint16 => 512,
+            Dtype::Uint32 => 768,
+            Dtype::Int64 => 1024,
+            Dtype::Uint64 => 1280,
+        }
+    }
+
+    /// The datatype with the given NIfTI `datatype` code, if it is supported.
+    pub fn from_code(code: i16) -> Option<Self> {
+        Dtype::ALL.into_iter().find(|dtype| dtype.code() == code)
+    }
+
+    /// Number of bits per voxel.
+    pub fn bitpix(self) -> i16 {
+        match self {
+            Dtype::Uint8 | Dtype::Int8 => 8,
+            Dtype::Uint16 | Dtype::Int16 => 16,
+            Dtype::Uint32 | Dtype::Int32 | Dtype::Float32 => 32,
+            Dtype::Uint64 | Dtype::Int64 | Dtype::Float64 => 64,
+        }
+    }
+
+    /// Whether this is an integer datatype.
+    pub fn is_integer(self) -> bool {
+        !matches!(self, Dtype::Float32 | Dtype::Float64)
+    }
+
+    /// Smallest and largest value that can be stored with this datatype.
+    pub fn range(self) -> (f64, f64) {
+        match self {
+            Dtype::Uint8 => (u8::MIN as f64, u8::MAX as f64),
+            Dtype::Int8 => (i8::MIN as f64, i8::MAX as f64),
+            Dtype::Uint16 => (u16::MIN as f64, u16::MAX as f64),
+            Dtype::Int16 => (i16::MIN as f64, i16::MAX as f64),
+            Dtype::Uint32 => (u32::MIN as f64, u32::MAX as f64),
+            Dtype::Int32 => (i32::MIN as f64, i32::MAX as f64),
+            Dtype::Uint64 => (u64::MIN as f64, u64::MAX as f64),
+            Dtype::Int64 => (i64::MIN as f64, i64::MAX as f64),
+            Dtype::Float32 => (f32::MIN as f64, f32::MAX as f64),
+            Dtype::Float64 => (f64::MIN, f64::MAX),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Dtype::Uint8 => "uint8",
+            Dtype::Int8 => "int8",
+            Dtype::Uint16 => "uint16",
+            Dtype::Int16 => "int16",
+            Dtype::Uint32 => "uint32",
+            Dtype::Int32 => "int32",
+            Dtype::Uint64 => "uint64",
+            Dtype::Int64 => "int64",
+            Dtype::Float32 => "float32",
+            Dtype::Float64 => "float64",
         }
     }
 }
 
+impl fmt::Display for Dtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Dtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Dtype::ALL
+            .into_iter()
+            .find(|dtype| dtype.name() == s)
+            .ok_or_else(|| format!("unknown datatype '{}'", s))
+    }
+}
+
+/// How to choose the datatype when saving an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtypePolicy {
+    /// Keep the datatype of the image header, i.e. usually the datatype of
+    /// the file the image was loaded from.
+    Keep,
+    /// Always use the given datatype.
+    Fixed(Dtype),
+    /// Use the smallest of uint8, int16, int32 and int64 that stores all
+    /// values without loss (e.g. for parcellations), and float32 if the data
+    /// are not all finite integers.
+    SmallestInteger,
+}
+
+impl FromStr for DtypePolicy {
+    type Err = String;
+
+    /// Parse "keep", "auto" or the name of a datatype (e.g. "int16").
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(DtypePolicy::Keep),
+            "auto" => Ok(DtypePolicy::SmallestInteger),
+            _ => s.parse().map(DtypePolicy::Fixed).map_err(|e| {
+                format!("{}, expected 'keep', 'auto' or a datatype", e)
+            }),
+        }
+    }
+}
+
+// Finite minimum and maximum of the data, or None if there are no finite
+// values.
+fn _finite_range(data: &Array<f32, IxDyn>) -> Option<(f64, f64)> {
+    data.iter()
+        .filter(|v| v.is_finite())
+        .fold(None, |range, &v| {
+            let v = v as f64;
+            match range {
+                None => Some((v, v)),
+                Some((min, max)) => Some((v.min(min), v.max(max))),
+            }
+        })
+}
+
+fn _smallest_integer_dtype(data: &Array<f32, IxDyn>) -> Option<Dtype> {
+    if data.iter().any(|v| !v.is_finite() || v.fract() != 0.) {
+        return None;
+    }
+    let (min, max) = _finite_range(data).unwrap_or((0., 0.));
+    [Dtype::Uint8, Dtype::Int16, Dtype::Int32, Dtype::Int64]
+        .into_iter()
+        .find(|dtype| {
+            let (lower, upper) = dtype.range();
+            lower <= min && max <= upper
+        })
+}
+
+// Compute scl_slope and scl_inter such that the data can be stored with the
+// given datatype.
+fn _scaling_for_dtype(data: &Array<f32, IxDyn>, dtype: Dtype) -> (f64, f64) {
+    if !dtype.is_integer() {
+        return (1., 0.);
+    }
+    let (min, max) = match _finite_range(data) {
+        Some(range) => range,
+        None => return (1., 0.),
+    };
+    let (lower, upper) = dtype.range();
+    let is_integral = data
+        .iter()
+        .filter(|v| v.is_finite())
+        .all(|v| v.fract() == 0.);
+    if is_integral && lower <= min && max <= upper {
+        return (1., 0.);
+    }
+    if min == max {
+        return (1., min);
+    }
+    let slope = (max - min) / (upper - lower);
+    (slope, min - lower * slope)
+}
+
+/// The three affines a NIfTI header can describe.
+///
+/// See [the nibabel documentation](https://nipy.org/nibabel/nifti_images.html#the-nifti-affines)
+/// for a description of each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffineKind {
+    /// Affine given by the `srow_x`, `srow_y` and `srow_z` fields.
+    Sform,
+    /// Affine given by the quaternion, the offsets, `pixdim` and qfac.
+    Qform,
+    /// Affine built from the voxel sizes only, centered on the volume.
+    Fallback,
+}
+
 /// Extract the affine matrix from a NiftiHeader.
-/// 
-/// At the moment, the function only returns the sform affine. I need to still
-/// implement obtaining the qform or fall-back affines if appropriate.
-/// The function should eventually follow [the conventions outlined by the 
-/// Python-based nibabel library](https://nipy.org/nibabel/nifti_images.html#choosing-the-image-affine):
+///
+/// The function follows [the conventions outlined by the Python-based
+/// nibabel library](https://nipy.org/nibabel/nifti_images.html#choosing-the-image-affine):
 ///
 /// > 1. If sform_code != 0 (‘unknown’) use the sform affine; else
 /// > 2. If qform_code != 0 (‘unknown’) use the qform affine; else
 /// > 3. Use the fall-back affine.
 ///
+/// Use `select_affine` to also find out which of the three was chosen, or
+/// `get_affine_of_kind` to force one of them.
+///
 /// Parameters
 /// ----------
-/// header : Header metadata for the NIfTI image to be stored.
+/// header : Header metadata of the NIfTI image.
 ///
 pub fn get_affine(header: &NiftiHeader) -> Array2<f32> {
+    select_affine(header).0
+}
+
+/// Extract the affine matrix from a NiftiHeader following the nibabel
+/// conventions (see `get_affine`), and return it together with the kind of
+/// affine that was chosen.
+///
+/// Parameters
+/// ----------
+/// header : Header metadata of the NIfTI image.
+///
+pub fn select_affine(header: &NiftiHeader) -> (Array2<f32>, AffineKind) {
+    let kind = if header.sform_code != 0 {
+        AffineKind::Sform
+    } else if header.qform_code != 0 {
+        AffineKind::Qform
+    } else {
+        AffineKind::Fallback
+    };
+    (get_affine_of_kind(header, kind), kind)
+}
+
+/// Build a specific affine matrix from a NiftiHeader, regardless of the
+/// sform and qform codes.
+///
+/// Parameters
+/// ----------
+/// header : Header metadata of the NIfTI image.
+///
+/// kind : Which of the three affines to build.
+///
+pub fn get_affine_of_kind(
+    header: &NiftiHeader,
+    kind: AffineKind,
+) -> Array2<f32> {
+    match kind {
+        AffineKind::Sform => _sform_affine(header),
+        AffineKind::Qform => _qform_affine(header),
+        AffineKind::Fallback => _fallback_affine(header),
+    }
+}
+
+fn _sform_affine(header: &NiftiHeader) -> Array2<f32> {
     arr2(&[
         header.srow_x,
         header.srow_y,
@@ -94,6 +853,181 @@ pub fn get_affine(header: &NiftiHeader) -> Array2<f32> {
     ])
 }
 
+// Quaternion to rotation matrix as in `nifti_quatern_to_mat44` of nifti1_io.c
+fn _qform_affine(header: &NiftiHeader) -> Array2<f32> {
+    let b = header.quatern_b as f64;
+    let c = header.quatern_c as f64;
+    let d = header.quatern_d as f64;
+    let (a, b, c, d) = {
+        let a_squared = 1.0 - (b * b + c * c + d * d);
+        if a_squared < 1e-7 {
+            // special case: 180 degree rotation, re-normalise b, c, d
+            let norm = (b * b + c * c + d * d).sqrt();
+            (0.0, b / norm, c / norm, d / norm)
+        } else {
+            (a_squared.sqrt(), b, c, d)
+        }
+    };
+
+    let rotation = [
+        [
+            a * a + b * b - c * c - d * d,
+            2. * (b * c - a * d),
+            2. * (b * d + a * c),
+        ],
+        [
+            2. * (b * c + a * d),
+            a * a + c * c - b * b - d * d,
+            2. * (c * d - a * b),
+        ],
+        [
+            2. * (b * d - a * c),
+            2. * (c * d + a * b),
+            a * a + d * d - c * c - b * b,
+        ],
+    ];
+
+    // non-positive voxel sizes are invalid and replaced by 1
+    let zoom = |z: f32| if z > 0. { z as f64 } else { 1.0 };
+    let qfac = if header.pixdim[0] < 0. { -1.0 } else { 1.0 };
+    let zooms = [
+        zoom(header.pixdim[1]),
+        zoom(header.pixdim[2]),
+        zoom(header.pixdim[3]) * qfac,
+    ];
+    let offsets = [header.quatern_x, header.quatern_y, header.quatern_z];
+
+    let mut affine = Array2::<f32>::eye(4);
+    for row in 0..3 {
+        for col in 0..3 {
+            affine[[row, col]] = (rotation[row][col] * zooms[col]) as f32;
+        }
+        affine[[row, 3]] = offsets[row];
+    }
+    affine
+}
+
+// Same as nibabel's `get_base_affine`: voxel sizes on the diagonal with a
+// flipped x axis and the origin in the center of the volume.
+fn _fallback_affine(header: &NiftiHeader) -> Array2<f32> {
+    let n_dims = (header.dim[0] as usize).clamp(1, 7);
+    let mut affine = Array2::<f32>::eye(4);
+    for axis in 0..3 {
+        let n_voxels = if axis < n_dims {
+            header.dim[axis + 1] as f32
+        } else {
+            1.
+        };
+        let zoom = if header.pixdim[axis + 1] == 0. {
+            1.
+        } else {
+            header.pixdim[axis + 1]
+        };
+        let zoom = if axis == 0 { -zoom } else { zoom };
+        affine[[axis, axis]] = zoom;
+        affine[[axis, 3]] = -(n_voxels - 1.) / 2. * zoom;
+    }
+    affine
+}
+
+// Quaternion, offsets, voxel sizes and qfac of an affine, as in
+// `nifti_mat44_to_quatern` of nifti1_io.c, except that the rotation is
+// orthogonalized with Gram-Schmidt instead of a polar decomposition.
+fn _set_qform(header: &mut NiftiHeader, affine: &Array2<f32>) {
+    let mut r = [[0f64; 3]; 3];
+    let mut zooms = [1f64; 3];
+    for col in 0..3 {
+        let column: Vec<f64> =
+            (0..3).map(|row| affine[[row, col]] as f64).collect();
+        let norm = column.iter().map(|v| v * v).sum::<f64>().sqrt();
+        for (row, r_row) in r.iter_mut().enumerate() {
+            r_row[col] = if norm > 0. {
+                column[row] / norm
+            } else {
+                (row == col) as u8 as f64
+            };
+        }
+        if norm > 0. {
+            zooms[col] = norm;
+        }
+    }
+    // make the columns orthonormal
+    for col in 1..3 {
+        for prev in 0..col {
+            let dot: f64 = r.iter().map(|row| row[col] * row[prev]).sum();
+            for row in r.iter_mut() {
+                row[col] -= dot * row[prev];
+            }
+        }
+        let norm = r.iter().map(|row| row[col].powi(2)).sum::<f64>().sqrt();
+        for row in r.iter_mut() {
+            row[col] /= norm;
+        }
+    }
+
+    let det = r[0][0] * (r[1][1] * r[2][2] - r[1][2] * r[2][1])
+        - r[0][1] * (r[1][0] * r[2][2] - r[1][2] * r[2][0])
+        + r[0][2] * (r[1][0] * r[2][1] - r[1][1] * r[2][0]);
+    let qfac = if det > 0. { 1. } else { -1. };
+    if qfac < 0. {
+        for row in r.iter_mut() {
+            row[2] = -row[2];
+        }
+    }
+
+    let trace = r[0][0] + r[1][1] + r[2][2] + 1.;
+    let (a, b, c, d) = if trace > 0.5 {
+        let a = 0.5 * trace.sqrt();
+        (
+            a,
+            0.25 * (r[2][1] - r[1][2]) / a,
+            0.25 * (r[0][2] - r[2][0]) / a,
+            0.25 * (r[1][0] - r[0][1]) / a,
+        )
+    } else {
+        let xd = 1. + r[0][0] - (r[1][1] + r[2][2]);
+        let yd = 1. + r[1][1] - (r[0][0] + r[2][2]);
+        let zd = 1. + r[2][2] - (r[0][0] + r[1][1]);
+        if xd > 1. {
+            let b = 0.5 * xd.sqrt();
+            (
+                0.25 * (r[2][1] - r[1][2]) / b,
+                b,
+                0.25 * (r[0][1] + r[1][0]) / b,
+                0.25 * (r[0][2] + r[2][0]) / b,
+            )
+        } else if yd > 1. {
+            let c = 0.5 * yd.sqrt();
+            (
+                0.25 * (r[0][2] - r[2][0]) / c,
+                0.25 * (r[0][1] + r[1][0]) / c,
+                c,
+                0.25 * (r[1][2] + r[2][1]) / c,
+            )
+        } else {
+            let d = 0.5 * zd.sqrt();
+            (
+                0.25 * (r[1][0] - r[0][1]) / d,
+                0.25 * (r[0][2] + r[2][0]) / d,
+                0.25 * (r[1][2] + r[2][1]) / d,
+                d,
+            )
+        }
+    };
+    // the qform requires a non-negative a
+    let (b, c, d) = if a < 0. { (-b, -c, -d) } else { (b, c, d) };
+    header.quatern_b = b as f32;
+    header.quatern_c = c as f32;
+    header.quatern_d = d as f32;
+    header.quatern_x = affine[[0, 3]];
+    header.quatern_y = affine[[1, 3]];
+    header.quatern_z = affine[[2, 3]];
+    header.pixdim[0] = qfac as f32;
+    for (axis, zoom) in zooms.iter().enumerate() {
+        header.pixdim[axis + 1] = *zoom as f32;
+    }
+}
+
 /// Convert voxel coordinates into "real-world" coordinates of the reference
 /// space. Practically, the function can also be used to transform the
 /// "real-world" coordinates into voxel coordinates by providing the inverse
@@ -123,11 +1057,20 @@ pub fn coord_transform(
 /// "real-world" coordinates into voxel-coordinates of the source array.
 /// For more info on coordinate systems and affines, see
 /// [the excellent nibabel documentation](https://nipy.org/nibabel/coordinate_systems.html).
-/// Currently, this function only uses "nearest-neighbour" interpolation, i.e.
-/// by simply using the source voxel coordinates to select the value from the
-/// source array and assigning it to the corresponding voxel coordinate in the
-/// target array.
-/// There are plans to add further interpolation techniques. 
+/// The source coordinates are computed on the fly for each target voxel and
+/// the target voxels are processed in parallel, so that the memory needed is
+/// proportional to the output only.
+/// With `Interpolation::Nearest`, the source voxel coordinates are simply
+/// used to select the value from the source array and assign it to the
+/// corresponding voxel coordinate in the target array, which is what is
+/// needed for parcellations and other label images. With
+/// `Interpolation::Trilinear`, the value is a weighted average of the eight
+/// neighbouring source voxels, which gives smoother results for continuous
+/// images such as T1s, tSNR or statistical maps. `Interpolation::Cubic`
+/// interpolates with cubic B-splines after spline prefiltering of the source,
+/// like `scipy.ndimage.map_coordinates` with `order=3` (nilearn's
+/// "continuous" interpolation), which preserves detail best when upsampling
+/// anatomical images.
 ///
 /// Parameters
 /// ----------
@@ -139,65 +1082,1112 @@ pub fn coord_transform(
 ///
 /// target_shape : shape of the output array, i.e. the shape of the image after
 /// resampling.
-pub fn resample_3d_nifti(
-    source: &Array<f32, Ix3>,
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of target voxels outside of the field of view of the
+/// source, e.g. 0 (as in nilearn) or NaN.
+///
+/// Returns an `Affine` error if the source affine cannot be inverted.
+pub fn resample_3d_nifti<S>(
+    source: &ArrayBase<S, Ix3>,
     source_affine: &Array2<f32>,
     target_affine: &Array2<f32>,
     target_shape: (usize, usize, usize),
-) -> Array<f32, Ix3> {
-    let mut resampled_data: Array<f32, Ix3> = Array::zeros(target_shape);
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<Array<f32, Ix3>>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    let transform = _target_to_source(source_affine, target_affine)?;
+    let mut resampled_data = Array::zeros(target_shape);
+    _resample_volume(
+        source,
+        &_affine_coordinates(&transform),
+        resampled_data.view_mut(),
+        interpolation,
+        fill_value,
+    );
+    Ok(resampled_data)
+}
 
+/// Resample a 4D NIfTI image volume by volume.
+///
+/// The transform from target to source voxels is computed once and every
+/// volume is then resampled in parallel as described for
+/// `resample_3d_nifti`. The fourth dimension of the output is the same as
+/// that of the source.
+///
+/// Parameters
+/// ----------
+/// source : ndarray containing the data of the image that is to be resampled.
+///
+/// source_affine : affine matrix that belongs to the image to be resampled.
+///
+/// target_affine : affine matrix of the image that is to be matched.
+///
+/// target_shape : spatial shape of the output array.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of target voxels outside of the field of view of the
+/// source.
+///
+/// Returns an `Affine` error if the source affine cannot be inverted.
+pub fn resample_4d_nifti<S>(
+    source: &ArrayBase<S, Ix4>,
+    source_affine: &Array2<f32>,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<Array<f32, Ix4>>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    let transform = _target_to_source(source_affine, target_affine)?;
+    let resampled_data = _resample_volumes(
+        source,
+        &_affine_coordinates(&transform),
+        target_shape,
+        interpolation,
+        fill_value,
+    );
+    Ok(resampled_data)
+}
+
+/// A dense displacement field defined on a grid in world space, e.g. a
+/// nonlinear registration estimated with ANTs or FSL FNIRT.
+///
+/// The displacement of each voxel is a vector in RAS world coordinates (mm),
+/// which is added to the world coordinates of the voxel to obtain the
+/// corresponding point in the moving image. Like the transforms of
+/// `nirust::transforms`, the field thus "pulls" the moving image onto the
+/// grid of the reference it was estimated for. Outside of its grid, the
+/// displacement is zero.
+#[derive(Debug, Clone)]
+pub struct DisplacementField {
+    // x, y and z components of the displacements
+    components: [Array3<f32>; 3],
+    affine: Array2<f32>,
+    affine_inv: Array2<f32>,
+}
+
+impl DisplacementField {
+    /// Create a field from displacements with shape (x, y, z, 3) in RAS world
+    /// coordinates and the affine of their grid.
+    pub fn new(
+        displacements: Array4<f32>,
+        affine: Array2<f32>,
+    ) -> Result<Self> {
+        if displacements.shape()[3] != 3 {
+            return Err(NirustError::Shape(format!(
+                "displacements must have shape (x, y, z, 3), got {:?}",
+                displacements.shape()
+            )));
+        }
+        let affine_inv = affine.inv().map_err(|e| {
+            NirustError::Affine(format!(
+                "affine of the displacement field is singular ({})",
+                e
+            ))
+        })?;
+        let components = [0, 1, 2].map(|axis| {
+            displacements
+                .index_axis(Axis(3), axis)
+                .as_standard_layout()
+                .to_owned()
+        });
+        Ok(DisplacementField {
+            components,
+            affine,
+            affine_inv,
+        })
+    }
+
+    /// Create a field from an image with three displacement volumes, either
+    /// 4D with shape (x, y, z, 3) or 5D with shape (x, y, z, 1, 3) as written
+    /// by ITK and ANTs.
+    ///
+    /// Parameters
+    /// ----------
+    /// image : Image with the displacements, on the grid of the reference.
+    ///
+    /// to_ras : 3x3 matrix that converts the stored displacement vectors to
+    /// RAS world coordinates, e.g. a flip of the x and y axes for the LPS
+    /// vectors of ITK, or the identity for RAS vectors.
+    ///
+    pub fn from_img(image: &NiftiImage, to_ras: &Array2<f32>) -> Result<Self> {
+        let data = image.data().view();
+        let data = match data.shape() {
+            [_, _, _, 3] => data,
+            [_, _, _, 1, 3] => data.index_axis_move(Axis(3), 0),
+            shape => {
+                return Err(NirustError::Shape(format!(
+                    "a displacement field needs three volumes, got an \
+                    image with shape {:?}",
+                    shape
+                )))
+            }
+        };
+        let data = data.into_dimensionality::<Ix4>().unwrap();
+        let mut displacements = Array4::zeros(data.raw_dim());
+        Zip::from(displacements.lanes_mut(Axis(3)))
+            .and(data.lanes(Axis(3)))
+            .for_each(|mut ras, stored| ras.assign(&to_ras.dot(&stored)));
+        DisplacementField::new(displacements, image.affine().clone())
+    }
+
+    /// Affine of the grid of the field.
+    pub fn affine(&self) -> &Array2<f32> {
+        &self.affine
+    }
+
+    /// Shape of the grid of the field.
+    pub fn shape(&self) -> (usize, usize, usize) {
+        self.components[0].dim()
+    }
+
+    /// Displacement at a point in world coordinates, interpolated trilinearly
+    /// between the voxels of the field.
+    pub fn displacement(&self, x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+        let (i, j, k) = _apply_affine(&self.affine_inv, x, y, z);
+        let (x_dim, y_dim, z_dim) = self.shape();
+        if !(_in_field(i, x_dim) && _in_field(j, y_dim) && _in_field(k, z_dim))
+        {
+            return (0., 0., 0.);
+        }
+        let [dx, dy, dz] = &self.components;
+        (
+            _trilinear(dx, i, j, k),
+            _trilinear(dy, i, j, k),
+            _trilinear(dz, i, j, k),
+        )
+    }
+}
+
+/// Resample a 3D NIfTI image through a displacement field.
+///
+/// For each target voxel, the displacement at its world coordinates is added
+/// to them, and the source is sampled at the resulting point as described for
+/// `resample_3d_nifti`. To compose the field with an affine transform that
+/// is applied after it (e.g. the affine part of an ANTs registration), use
+/// the affine of the source in the world space of the field as
+/// `source_affine`, see `resample_to_img_with_field`.
+///
+/// Parameters
+/// ----------
+/// source : ndarray containing the data of the image that is to be resampled.
+///
+/// source_affine : affine matrix that belongs to the image to be resampled.
+///
+/// field : Displacement field mapping target world coordinates to source
+/// world coordinates.
+///
+/// target_affine : affine matrix of the image that is to be matched.
+///
+/// target_shape : shape of the output array.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of target voxels outside of the field of view of the
+/// source.
+///
+/// Returns an `Affine` error if the source affine cannot be inverted.
+pub fn resample_3d_with_field<S>(
+    source: &ArrayBase<S, Ix3>,
+    source_affine: &Array2<f32>,
+    field: &DisplacementField,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<Array<f32, Ix3>>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    let source_affine_inv = _source_affine_inv(source_affine)?;
+    let mut resampled_data = Array::zeros(target_shape);
+    _resample_volume(
+        source,
+        &_field_coordinates(&source_affine_inv, field, target_affine),
+        resampled_data.view_mut(),
+        interpolation,
+        fill_value,
+    );
+    Ok(resampled_data)
+}
+
+/// Resample a 4D NIfTI image through a displacement field, volume by volume.
+///
+/// See `resample_3d_with_field` for the parameters.
+pub fn resample_4d_with_field<S>(
+    source: &ArrayBase<S, Ix4>,
+    source_affine: &Array2<f32>,
+    field: &DisplacementField,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<Array<f32, Ix4>>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    let source_affine_inv = _source_affine_inv(source_affine)?;
+    let resampled_data = _resample_volumes(
+        source,
+        &_field_coordinates(&source_affine_inv, field, target_affine),
+        target_shape,
+        interpolation,
+        fill_value,
+    );
+    Ok(resampled_data)
+}
+
+// Source voxel coordinates of the target voxel (i, j, k): the world
+// coordinates of the target voxel, displaced by the field and mapped to
+// source voxels with the inverse of the source affine.
+fn _field_coordinates<'a>(
+    source_affine_inv: &'a Array2<f32>,
+    field: &'a DisplacementField,
+    target_affine: &'a Array2<f32>,
+) -> impl Fn(usize, usize, usize) -> (f32, f32, f32) + Sync + 'a {
+    move |i, j, k| {
+        let (x, y, z) =
+            _apply_affine(target_affine, i as f32, j as f32, k as f32);
+        let (dx, dy, dz) = field.displacement(x, y, z);
+        _apply_affine(source_affine_inv, x + dx, y + dy, z + dz)
+    }
+}
+
+// Affine that maps target voxel indices to source voxel indices, i.e. the
+// inverse of the source affine composed with the target affine.
+fn _target_to_source(
+    source_affine: &Array2<f32>,
+    target_affine: &Array2<f32>,
+) -> Result<Array2<f32>> {
+    Ok(_source_affine_inv(source_affine)?.dot(target_affine))
+}
+
+fn _source_affine_inv(source_affine: &Array2<f32>) -> Result<Array2<f32>> {
+    source_affine.inv().map_err(|e| {
+        NirustError::Affine(format!("source affine is singular ({})", e))
+    })
+}
+
+// Source voxel coordinates of the target voxel (i, j, k) for a transform
+// from target to source voxels.
+fn _affine_coordinates(
+    m: &Array2<f32>,
+) -> impl Fn(usize, usize, usize) -> (f32, f32, f32) + Sync + '_ {
+    move |i, j, k| _apply_affine(m, i as f32, j as f32, k as f32)
+}
+
+// Apply a 4x4 affine to a point, like `coord_transform` but without
+// allocating, for use in per-voxel loops.
+fn _apply_affine(m: &Array2<f32>, x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+    (
+        m[[0, 0]] * x + m[[0, 1]] * y + m[[0, 2]] * z + m[[0, 3]],
+        m[[1, 0]] * x + m[[1, 1]] * y + m[[1, 2]] * z + m[[1, 3]],
+        m[[2, 0]] * x + m[[2, 1]] * y + m[[2, 2]] * z + m[[2, 3]],
+    )
+}
+
+// Resample all volumes of a 4D array in parallel, see `_resample_volume`.
+fn _resample_volumes<S, F>(
+    source: &ArrayBase<S, Ix4>,
+    coordinates: &F,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Array<f32, Ix4>
+where
+    S: Data<Elem = f32> + Sync,
+    F: Fn(usize, usize, usize) -> (f32, f32, f32) + Sync,
+{
+    let (x_dim, y_dim, z_dim) = target_shape;
+    let n_volumes = source.shape()[3];
+    info!("Resampling {} volumes...", n_volumes);
+
+    let mut resampled_data = Array::zeros((x_dim, y_dim, z_dim, n_volumes));
+    resampled_data
+        .axis_iter_mut(Axis(3))
+        .into_par_iter()
+        .zip(source.axis_iter(Axis(3)).into_par_iter())
+        .for_each(|(target, volume)| {
+            _resample_volume(
+                &volume,
+                coordinates,
+                target,
+                interpolation,
+                fill_value,
+            )
+        });
+    resampled_data
+}
+
+// Resample a single volume into `target`. The source coordinates of each
+// target voxel are computed on the fly with `coordinates`, and the voxels
+// are processed in parallel, so that no memory beyond the output is needed
+// (except for the spline coefficients of cubic interpolation).
+fn _resample_volume<S, F>(
+    source: &ArrayBase<S, Ix3>,
+    coordinates: &F,
+    target: ArrayViewMut3<f32>,
+    interpolation: Interpolation,
+    fill_value: f32,
+) where
+    S: Data<Elem = f32> + Sync,
+    F: Fn(usize, usize, usize) -> (f32, f32, f32) + Sync,
+{
     let x_dim_src = source.shape()[0];
     let y_dim_src = source.shape()[1];
     let z_dim_src = source.shape()[2];
+    let in_field = |x: f32, y: f32, z: f32| {
+        _in_field(x, x_dim_src)
+            && _in_field(y, y_dim_src)
+            && _in_field(z, z_dim_src)
+    };
+    let coefficients = match interpolation {
+        Interpolation::Cubic => Some(_spline_filter(source)),
+        _ => None,
+    };
 
-    let x_dim_targ = resampled_data.shape()[0];
-    let y_dim_targ = resampled_data.shape()[1];
-    let z_dim_targ = resampled_data.shape()[2];
+    Zip::indexed(target).par_for_each(|(i, j, k), value| {
+        let (x, y, z) = coordinates(i, j, k);
 
-    // transform coords for reference
-    let mut target_indices = Array::zeros((4, resampled_data.len()));
-    for (row, (i, j, k)) in
-        iproduct!(0..x_dim_targ, 0..y_dim_targ, 0..z_dim_targ).enumerate()
-    {
-        target_indices
-            .slice_mut(s![.., row])
-            .assign(&array!(i as f32, j as f32, k as f32, 1.));
-    }
-    let source_indices = source_affine
-        .inv()
-        .unwrap()
-        .dot(target_affine)
-        .dot(&target_indices);
-
-    for (col_src, col_targ) in source_indices
-        .axis_iter(Axis(1))
-        .zip(target_indices.axis_iter(Axis(1)))
+        *value = match interpolation {
+            Interpolation::Nearest => match (
+                _handle_index_format(x, x_dim_src),
+                _handle_index_format(y, y_dim_src),
+                _handle_index_format(z, z_dim_src),
+            ) {
+                (Some(i_src), Some(j_src), Some(k_src)) => {
+                    source[[i_src, j_src, k_src]]
+                }
+                _ => fill_value,
+            },
+            Interpolation::Trilinear if in_field(x, y, z) => {
+                _trilinear(source, x, y, z)
+            }
+            Interpolation::Cubic if in_field(x, y, z) => {
+                _cubic_bspline(coefficients.as_ref().unwrap(), x, y, z)
+            }
+            _ => fill_value,
+        };
+    });
+}
+
+/// Interpolation methods for resampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// Value of the closest source voxel, for label images.
+    #[default]
+    Nearest,
+    /// Weighted average of the eight neighbouring source voxels, for
+    /// continuous images.
+    Trilinear,
+    /// Cubic B-spline interpolation with spline prefiltering, for continuous
+    /// images. Slower than trilinear interpolation, but sharper. NaNs in the
+    /// source spread to the whole image, as with scipy.
+    Cubic,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    /// Parse "nearest", "trilinear" or "cubic" (or "continuous", as in
+    /// nilearn).
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Interpolation::Nearest),
+            "trilinear" => Ok(Interpolation::Trilinear),
+            "cubic" | "continuous" => Ok(Interpolation::Cubic),
+            _ => Err(format!(
+                "unknown interpolation '{}', expected 'nearest', \
+                'trilinear' or 'cubic'",
+                s
+            )),
+        }
+    }
+}
+
+/// Resample a 3D or 4D image to the grid of a reference image.
+///
+/// The resampled image has the spatial shape and affine of `target` and the
+/// datatype of `source`, see `resample_3d_nifti` for details on how the values
+/// are obtained. 4D images are resampled volume by volume, and keep their
+/// number of volumes and their TR.
+///
+/// Parameters
+/// ----------
+/// source : 3D or 4D image to be resampled.
+///
+/// target : 3D or 4D image whose grid is to be matched.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of voxels outside of the field of view of `source`.
+///
+pub fn resample_to_img(
+    source: &NiftiImage,
+    target: &NiftiImage,
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    resample_to_img_with_affine(
+        source,
+        source.affine(),
+        None,
+        target,
+        interpolation,
+        fill_value,
+    )
+}
+
+// Resample an image to the grid of `target`, placing the data of `source`
+// in world space with `source_affine` instead of its own affine and
+// optionally sampling it through a displacement field, e.g. to apply a
+// registration in the same interpolation step.
+pub(crate) fn resample_to_img_with_affine(
+    source: &NiftiImage,
+    source_affine: &Array2<f32>,
+    field: Option<&DisplacementField>,
+    target: &NiftiImage,
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    let resampled = _resample_data(
+        source,
+        source_affine,
+        field,
+        target.affine(),
+        target.spatial_shape(),
+        interpolation,
+        fill_value,
+    )?;
+    let mut resampled = target.new_img_like(resampled)?;
+    if let Some(dtype) = source.dtype() {
+        resampled.set_dtype(dtype);
+    }
+    if source.ndim() == 4 {
+        // the time axis and its units come from the source
+        resampled.header.pixdim[4] = source.header.pixdim[4];
+        resampled.header.xyzt_units = (resampled.header.xyzt_units & 0x07)
+            | (source.header.xyzt_units & 0x38);
+        resampled.header.toffset = source.header.toffset;
+    }
+    Ok(resampled)
+}
+
+/// Resample a 3D or 4D image to the grid of a reference image through a
+/// displacement field, optionally composed with an affine transform, in a
+/// single interpolation step.
+///
+/// Each point of the reference grid is first displaced by `field` and then
+/// mapped by `transform`, which is the order in which the nonlinear and the
+/// affine part of an ANTs registration (or an FSL FNIRT warp and its FLIRT
+/// pre-matrix) map reference points to the moving image. The resampled
+/// image is otherwise like the output of `resample_to_img`.
+///
+/// Parameters
+/// ----------
+/// source : 3D or 4D image to be resampled (the moving image).
+///
+/// target : Image whose grid is to be matched (the reference image).
+///
+/// field : Displacement field estimated for the reference image.
+///
+/// transform : Optional 4x4 affine mapping displaced RAS world coordinates
+/// to those of `source`, see `nirust::transforms::read_transform`.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of voxels outside of the field of view of `source`.
+///
+pub fn resample_to_img_with_field(
+    source: &NiftiImage,
+    target: &NiftiImage,
+    field: &DisplacementField,
+    transform: Option<&Array2<f32>>,
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    // the affine of the source in the world space of the displaced points
+    let source_affine = match transform {
+        Some(transform) if transform.dim() != (4, 4) => {
+            return Err(NirustError::Affine(format!(
+                "transform must be a 4x4 matrix, got {:?}",
+                transform.dim()
+            )))
+        }
+        Some(transform) => transform
+            .inv()
+            .map_err(|e| {
+                NirustError::Affine(format!("transform is singular ({})", e))
+            })?
+            .dot(source.affine()),
+        None => source.affine().clone(),
+    };
+    resample_to_img_with_affine(
+        source,
+        &source_affine,
+        Some(field),
+        target,
+        interpolation,
+        fill_value,
+    )
+}
+
+/// Resample a 3D or 4D image to a grid given by an affine and a shape.
+///
+/// The resampled image keeps the header of `source` (including the datatype
+/// and the TR of 4D images), with its sform and qform set to `target_affine`.
+///
+/// Parameters
+/// ----------
+/// source : 3D or 4D image to be resampled.
+///
+/// target_affine : 4x4 affine of the output grid.
+///
+/// target_shape : Spatial shape of the output grid.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of voxels outside of the field of view of `source`.
+///
+pub fn resample_img(
+    source: &NiftiImage,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    if target_affine.dim() != (4, 4) {
+        return Err(NirustError::Affine(format!(
+            "target affine must be a 4x4 matrix, got {:?}",
+            target_affine.dim()
+        )));
+    }
+    let resampled = _resample_data(
+        source,
+        source.affine(),
+        None,
+        target_affine,
+        target_shape,
+        interpolation,
+        fill_value,
+    )?;
+    let mut resampled = source.new_img_like(resampled)?;
+    resampled.set_affine(target_affine);
+    Ok(resampled)
+}
+
+/// Resample a 3D or 4D image to a new voxel size.
+///
+/// The axes of the output grid have the same directions as those of
+/// `source`, and its field of view is chosen such that it covers the whole
+/// input, as nilearn's `resample_img` does when given only a 3x3 affine.
+///
+/// Parameters
+/// ----------
+/// source : 3D or 4D image to be resampled.
+///
+/// voxel_size : Voxel size of the output along the three spatial axes, e.g.
+/// `(2., 2., 2.)` for 2 mm isotropic voxels.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of voxels outside of the field of view of `source`.
+///
+pub fn resample_to_voxel_size(
+    source: &NiftiImage,
+    voxel_size: (f32, f32, f32),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    let (vx, vy, vz) = voxel_size;
+    if [vx, vy, vz].iter().any(|v| !(v.is_finite() && *v > 0.)) {
+        return Err(NirustError::Argument(format!(
+            "voxel sizes must be positive, got {:?}",
+            voxel_size
+        )));
+    }
+    let mut zooms = source.affine().slice(s![0..3, 0..3]).to_owned();
+    for (mut column, size) in zooms.columns_mut().into_iter().zip([vx, vy, vz])
     {
-        let i_src = _handle_index_format(&col_src[0], &x_dim_src);
-        let j_src = _handle_index_format(&col_src[1], &y_dim_src);
-        let k_src = _handle_index_format(&col_src[2], &z_dim_src);
+        let norm = column.mapv(|v| v * v).sum().sqrt();
+        if norm == 0. {
+            return Err(NirustError::Affine(
+                "affine of the image is singular".to_string(),
+            ));
+        }
+        column *= size / norm;
+    }
+    let (target_affine, target_shape) =
+        _bounding_grid(source.affine(), source.spatial_shape(), &zooms)?;
+    info!(
+        "Resampling to voxel size {:?}, shape {:?}",
+        voxel_size, target_shape
+    );
+    resample_img(
+        source,
+        &target_affine,
+        target_shape,
+        interpolation,
+        fill_value,
+    )
+}
 
-        let i_targ = _handle_index_format(&col_targ[0], &x_dim_targ);
-        let j_targ = _handle_index_format(&col_targ[1], &y_dim_targ);
-        let k_targ = _handle_index_format(&col_targ[2], &z_dim_targ);
+// Resample the data of a 3D or 4D image, whose voxels are mapped to world
+// coordinates by `source_affine`.
+fn _resample_data(
+    source: &NiftiImage,
+    source_affine: &Array2<f32>,
+    field: Option<&DisplacementField>,
+    target_affine: &Array2<f32>,
+    target_shape: (usize, usize, usize),
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<Array<f32, IxDyn>> {
+    let resampled = match (source.ndim(), field) {
+        (0..=3, None) => resample_3d_nifti(
+            &source.data_3d()?,
+            source_affine,
+            target_affine,
+            target_shape,
+            interpolation,
+            fill_value,
+        )?
+        .into_dyn(),
+        (0..=3, Some(field)) => resample_3d_with_field(
+            &source.data_3d()?,
+            source_affine,
+            field,
+            target_affine,
+            target_shape,
+            interpolation,
+            fill_value,
+        )?
+        .into_dyn(),
+        (4, None) => resample_4d_nifti(
+            &source.data().view().into_dimensionality::<Ix4>().unwrap(),
+            source_affine,
+            target_affine,
+            target_shape,
+            interpolation,
+            fill_value,
+        )?
+        .into_dyn(),
+        (4, Some(field)) => resample_4d_with_field(
+            &source.data().view().into_dimensionality::<Ix4>().unwrap(),
+            source_affine,
+            field,
+            target_affine,
+            target_shape,
+            interpolation,
+            fill_value,
+        )?
+        .into_dyn(),
+        (n, _) => {
+            return Err(NirustError::Shape(format!(
+                "can only resample 3D or 4D images, got a {}D image",
+                n
+            )))
+        }
+    };
+    Ok(resampled)
+}
 
-        resampled_data
-            .slice_mut(s![i_targ, j_targ, k_targ])
-            .assign(&source.slice(s![i_src, j_src, k_src]));
+// Affine and shape of the smallest grid with the given rotation and zooms
+// (a 3x3 matrix) that contains the centers of all voxels of the source grid.
+fn _bounding_grid(
+    source_affine: &Array2<f32>,
+    source_shape: (usize, usize, usize),
+    zooms: &Array2<f32>,
+) -> Result<(Array2<f32>, (usize, usize, usize))> {
+    let zooms_inv = zooms.inv().map_err(|e| {
+        NirustError::Affine(format!("target affine is singular ({})", e))
+    })?;
+    let (nx, ny, nz) = source_shape;
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for (i, j, k) in iproduct!(
+        [0, nx.saturating_sub(1)],
+        [0, ny.saturating_sub(1)],
+        [0, nz.saturating_sub(1)]
+    ) {
+        let (x, y, z) =
+            coord_transform(i as f32, j as f32, k as f32, source_affine);
+        let corner = zooms_inv.dot(&array![x, y, z]);
+        for axis in 0..3 {
+            min[axis] = min[axis].min(corner[axis]);
+            max[axis] = max[axis].max(corner[axis]);
+        }
     }
+    // tolerate rounding errors so that grids that fit exactly do not grow
+    let n_voxels = |axis: usize| {
+        (max[axis] - min[axis] - 1e-3).ceil().max(0.) as usize + 1
+    };
+    let offset = zooms.dot(&array![min[0], min[1], min[2]]);
 
-    resampled_data
+    let mut affine = Array2::<f32>::eye(4);
+    affine.slice_mut(s![0..3, 0..3]).assign(zooms);
+    affine.slice_mut(s![0..3, 3]).assign(&offset);
+    Ok((affine, (n_voxels(0), n_voxels(1), n_voxels(2))))
+}
+
+// Round a continuous source coordinate to the nearest voxel index, or None if
+// it lies outside of the `x_max` voxels of the axis.
+fn _handle_index_format(x: f32, x_max: usize) -> Option<usize> {
+    let x = x.round();
+    if x >= 0. && x < x_max as f32 {
+        Some(x as usize)
+    } else {
+        None
+    }
+}
+
+// Whether a continuous source coordinate lies within the `x_max` voxels of
+// an axis, i.e. can be interpolated without extrapolating. A small tolerance
+// prevents rounding errors from dropping the border voxels. Nothing lies
+// within an axis without voxels.
+fn _in_field(x: f32, x_max: usize) -> bool {
+    const TOLERANCE: f32 = 1e-3;
+    x >= -TOLERANCE && x <= x_max as f32 - 1. + TOLERANCE
+}
+
+// Trilinear interpolation at continuous voxel coordinates, with coordinates
+// outside of the array clamped to its border.
+fn _trilinear<S>(source: &ArrayBase<S, Ix3>, x: f32, y: f32, z: f32) -> f32
+where
+    S: Data<Elem = f32>,
+{
+    // lower and upper neighbour and weight of the upper one along each axis
+    let mut neighbours = [(0, 0, 0f32); 3];
+    for (axis, c) in [x, y, z].into_iter().enumerate() {
+        let n = source.shape()[axis];
+        let c = c.clamp(0., (n - 1) as f32);
+        let lower = c.floor() as usize;
+        neighbours[axis] = (lower, (lower + 1).min(n - 1), c - lower as f32);
+    }
+    let corner = |axis: usize, upper: bool| {
+        let (lower_index, upper_index, weight) = neighbours[axis];
+        if upper {
+            (upper_index, weight)
+        } else {
+            (lower_index, 1. - weight)
+        }
+    };
+
+    let mut value = 0.;
+    for (ui, uj, uk) in iproduct!([false, true], [false, true], [false, true]) {
+        let (i, wi) = corner(0, ui);
+        let (j, wj) = corner(1, uj);
+        let (k, wk) = corner(2, uk);
+        let weight = wi * wj * wk;
+        // skip voxels without weight, so that NaNs do not spread
+        if weight != 0. {
+            value += weight * source[[i, j, k]];
+        }
+    }
+    value
+}
+
+// Pole of the cubic B-spline prefilter.
+const BSPLINE_POLE: f64 = -0.267_949_192_431_122_7; // sqrt(3) - 2
+
+// Cubic B-spline coefficients of a 3D array, obtained by applying the
+// recursive prefilter of Unser et al. along each axis with mirror boundaries
+// (as `scipy.ndimage.spline_filter` does), so that the spline interpolates
+// the original values at the voxel centers.
+fn _spline_filter<S>(source: &ArrayBase<S, Ix3>) -> Array3<f32>
+where
+    S: Data<Elem = f32>,
+{
+    let mut coefficients = source.mapv(|v| v as f64);
+    let mut line = Vec::new();
+    for axis in 0..3 {
+        for mut lane in coefficients.lanes_mut(Axis(axis)) {
+            line.clear();
+            line.extend(lane.iter());
+            _spline_filter_1d(&mut line);
+            lane.iter_mut().zip(&line).for_each(|(c, &v)| *c = v);
+        }
+    }
+    coefficients.mapv(|v| v as f32)
 }
 
-fn _handle_index_format(x: &f32, x_max: &usize) -> i32 {
-    let x_max_as_i = *x_max as i32;
-    let x_as_i = *x as i32;
-    if x_as_i < 0 {
-        0
-    } else if x_as_i >= x_max_as_i {
-        x_max_as_i - 1
+fn _spline_filter_1d(c: &mut [f64]) {
+    let n = c.len();
+    if n < 2 {
+        return;
+    }
+    let z = BSPLINE_POLE;
+    let gain = (1. - z) * (1. - 1. / z);
+    c.iter_mut().for_each(|v| *v *= gain);
+
+    // causal initialization for a mirror-symmetric signal
+    let mut zn = z;
+    let iz = 1. / z;
+    let mut sum = c[0] + z.powi(n as i32 - 1) * c[n - 1];
+    let mut z2n = z.powi(2 * n as i32 - 3);
+    for v in c.iter().take(n - 1).skip(1) {
+        sum += (zn + z2n) * v;
+        zn *= z;
+        z2n *= iz;
+    }
+    c[0] = sum / (1. - zn * zn);
+    for k in 1..n {
+        c[k] += z * c[k - 1];
+    }
+
+    // anti-causal initialization and filter
+    c[n - 1] = (z / (z * z - 1.)) * (z * c[n - 2] + c[n - 1]);
+    for k in (0..n - 1).rev() {
+        c[k] = z * (c[k + 1] - c[k]);
+    }
+}
+
+// Evaluate the cubic B-spline with the given coefficients at continuous voxel
+// coordinates, with coordinates outside of the array clamped to its border
+// and coefficients mirrored beyond it.
+fn _cubic_bspline(coefficients: &Array3<f32>, x: f32, y: f32, z: f32) -> f32 {
+    // indices and weights of the four neighbours along each axis
+    let mut neighbours = [([0usize; 4], [0f32; 4]); 3];
+    for (axis, c) in [x, y, z].into_iter().enumerate() {
+        let n = coefficients.shape()[axis];
+        let c = c.clamp(0., (n - 1) as f32);
+        let start = c.floor();
+        let t = c - start;
+        let weights = [
+            (1. - t).powi(3) / 6.,
+            (4. - 6. * t * t + 3. * t.powi(3)) / 6.,
+            (1. + 3. * t + 3. * t * t - 3. * t.powi(3)) / 6.,
+            t.powi(3) / 6.,
+        ];
+        let mut indices = [0usize; 4];
+        for (offset, index) in indices.iter_mut().enumerate() {
+            *index = _mirror_index(start as i64 + offset as i64 - 1, n);
+        }
+        neighbours[axis] = (indices, weights);
+    }
+
+    let (ix, wx) = neighbours[0];
+    let (iy, wy) = neighbours[1];
+    let (iz, wz) = neighbours[2];
+    let mut value = 0.;
+    for (a, b, c) in iproduct!(0..4, 0..4, 0..4) {
+        let weight = wx[a] * wy[b] * wz[c];
+        if weight != 0. {
+            value += weight * coefficients[[ix[a], iy[b], iz[c]]];
+        }
+    }
+    value
+}
+
+// Mirror an index at the borders of an axis with `n` voxels (d c b | a b c d
+// | c b a).
+fn _mirror_index(index: i64, n: usize) -> usize {
+    if n == 1 {
+        return 0;
+    }
+    let period = 2 * (n as i64 - 1);
+    let index = index.rem_euclid(period);
+    if index < n as i64 {
+        index as usize
     } else {
-        x_as_i
+        (period - index) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _assert_close(a: &Array2<f32>, b: &Array2<f32>) {
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-5, "{} != {}", a, b);
+        }
+    }
+
+    fn _coded_header() -> NiftiHeader {
+        NiftiHeader {
+            dim: [3, 4, 3, 2, 1, 1, 1, 1],
+            pixdim: [1., 2., 2.5, 3., 1., 1., 1., 1.],
+            qform_code: 1,
+            quatern_x: -4.,
+            quatern_y: 5.,
+            quatern_z: -6.,
+            sform_code: 4,
+            srow_x: [-2., 0., 0., 4.],
+            srow_y: [0., 2.5, 0., -5.],
+            srow_z: [0., 0., 3., 6.],
+            ..NiftiHeader::default()
+        }
+    }
+
+    #[test]
+    fn smallest_integer_dtype_fits_the_range() {
+        let dtype =
+            |values: &[f32]| _smallest_integer_dtype(&arr1(values).into_dyn());
+        assert_eq!(dtype(&[0., 3., 255.]), Some(Dtype::Uint8));
+        assert_eq!(dtype(&[-1., 3.]), Some(Dtype::Int16));
+        assert_eq!(dtype(&[0., 40000.]), Some(Dtype::Int32));
+        assert_eq!(dtype(&[0., 1.5]), None);
+        assert_eq!(dtype(&[0., f32::NAN]), None);
+    }
+
+    #[test]
+    fn scaling_for_dtype_stores_values_accurately() {
+        let integers = arr1(&[-3., 0., 200.]).into_dyn();
+        assert_eq!(_scaling_for_dtype(&integers, Dtype::Int16), (1., 0.));
+        let constant = arr1(&[2.5, 2.5]).into_dyn();
+        assert_eq!(_scaling_for_dtype(&constant, Dtype::Uint8), (1., 2.5));
+
+        let values = arr1(&[-1.25, 0.1, 3.7, 12.9]).into_dyn();
+        assert_eq!(_scaling_for_dtype(&values, Dtype::Float32), (1., 0.));
+        let (slope, inter) = _scaling_for_dtype(&values, Dtype::Int16);
+        // the range of the data is mapped onto the range of int16
+        assert!((slope - 14.15 / 65535.).abs() < 1e-9);
+        assert!((inter - 32768. * slope + 1.25).abs() < 1e-6);
+        for &value in values.iter() {
+            let stored = ((value as f64 - inter) / slope).round();
+            assert!((-32768. ..=32767.).contains(&stored));
+            assert!((stored * slope + inter - value as f64).abs() <= slope);
+        }
+    }
+
+    #[test]
+    fn select_affine_prefers_sform_over_qform() {
+        let mut header = _coded_header();
+        let (affine, kind) = select_affine(&header);
+        assert_eq!(kind, AffineKind::Sform);
+        assert_eq!(
+            affine,
+            arr2(&[
+                [-2., 0., 0., 4.],
+                [0., 2.5, 0., -5.],
+                [0., 0., 3., 6.],
+                [0., 0., 0., 1.],
+            ])
+        );
+
+        header.sform_code = 0;
+        let (affine, kind) = select_affine(&header);
+        assert_eq!(kind, AffineKind::Qform);
+        assert_eq!(
+            affine,
+            arr2(&[
+                [2., 0., 0., -4.],
+                [0., 2.5, 0., 5.],
+                [0., 0., 3., -6.],
+                [0., 0., 0., 1.],
+            ])
+        );
+    }
+
+    #[test]
+    fn fallback_affine_uses_pixdim_and_centers_the_volume() {
+        let header = NiftiHeader {
+            sform_code: 0,
+            qform_code: 0,
+            .._coded_header()
+        };
+        let (affine, kind) = select_affine(&header);
+        assert_eq!(kind, AffineKind::Fallback);
+        assert_eq!(
+            affine,
+            arr2(&[
+                [-2., 0., 0., 3.],
+                [0., 2.5, 0., -2.5],
+                [0., 0., 3., -1.5],
+                [0., 0., 0., 1.],
+            ])
+        );
+    }
+
+    #[test]
+    fn negative_qfac_flips_the_third_axis_of_the_qform() {
+        let mut header = _coded_header();
+        header.pixdim[0] = -1.;
+        let affine = get_affine_of_kind(&header, AffineKind::Qform);
+        assert_eq!(affine.column(2).to_vec(), vec![0., 0., -3., 0.]);
+
+        header.pixdim[0] = 1.;
+        let affine = get_affine_of_kind(&header, AffineKind::Qform);
+        assert_eq!(affine.column(2).to_vec(), vec![0., 0., 3., 0.]);
+    }
+
+    #[test]
+    fn qform_round_trips_an_oblique_affine() {
+        let (sin_x, cos_x) = 0.3f32.sin_cos();
+        let (sin_z, cos_z) = (-0.5f32).sin_cos();
+        let rotation_x =
+            arr2(&[[1., 0., 0.], [0., cos_x, -sin_x], [0., sin_x, cos_x]]);
+        let rotation_z =
+            arr2(&[[cos_z, -sin_z, 0.], [sin_z, cos_z, 0.], [0., 0., 1.]]);
+        // left-handed voxel axes, so that qfac is negative
+        let linear = rotation_z
+            .dot(&rotation_x)
+            .dot(&Array2::from_diag(&arr1(&[2., 2.5, -3.])));
+        let mut affine = Array2::<f32>::eye(4);
+        affine.slice_mut(s![..3, ..3]).assign(&linear);
+        affine
+            .slice_mut(s![..3, 3])
+            .assign(&arr1(&[-90., 12.5, 33.]));
+
+        let mut header = NiftiHeader::default();
+        _set_qform(&mut header, &affine);
+        assert_eq!(header.pixdim[0], -1.);
+        _assert_close(&_qform_affine(&header), &affine);
+    }
+
+    #[test]
+    fn resampling_an_empty_source_fills_the_target() {
+        let source = Array3::<f32>::zeros((0, 3, 3));
+        let affine = Array2::eye(4);
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Trilinear,
+            Interpolation::Cubic,
+        ] {
+            let resampled = resample_3d_nifti(
+                &source,
+                &affine,
+                &affine,
+                (2, 2, 2),
+                interpolation,
+                -1.,
+            )
+            .unwrap();
+            assert!(resampled.iter().all(|&v| v == -1.), "{:?}", interpolation);
+        }
+    }
+
+    #[test]
+    fn cubic_resampling_to_identity_grid_reproduces_input() {
+        let source = Array::from_shape_fn((7, 6, 5), |(i, j, k)| {
+            ((i * 7 + j * 3 + k * 5) % 11) as f32 - 0.5 * j as f32
+        });
+        let affine = arr2(&[
+            [2., 0., 0., -10.],
+            [0., 2., 0., 4.],
+            [0., 0., 3., 1.],
+            [0., 0., 0., 1.],
+        ]);
+        let resampled = resample_3d_nifti(
+            &source,
+            &affine,
+            &affine,
+            (7, 6, 5),
+            Interpolation::Cubic,
+            0.,
+        )
+        .unwrap();
+        for (a, b) in resampled.iter().zip(source.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
     }
 }
@@ -0,0 +1,181 @@
+//! The `nirust::info` module summarizes the header and affine of an image,
+//! either as human-readable text or as JSON for scripts.
+
+use ndarray::prelude::*;
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::image::{get_affine_of_kind, AffineKind, NiftiImage};
+use crate::orientation::axis_codes;
+
+/// Summary of the header, affine and intensities of an image.
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub format: String,
+    pub shape: Vec<usize>,
+    pub voxel_sizes: (f32, f32, f32),
+    pub datatype: String,
+    /// Repetition time in seconds, for 4D images.
+    pub tr: Option<f32>,
+    pub xyzt_units: u8,
+    pub spatial_unit: &'static str,
+    pub temporal_unit: &'static str,
+    pub sform_code: i16,
+    pub qform_code: i16,
+    pub sform: Array2<f32>,
+    pub qform: Array2<f32>,
+    /// The affine chosen by `get_affine`.
+    pub affine: Array2<f32>,
+    pub affine_kind: AffineKind,
+    /// Axis codes of the chosen affine, e.g. "RAS".
+    pub orientation: String,
+    /// Smallest and largest finite value of the data.
+    pub intensity_range: Option<(f32, f32)>,
+    pub descrip: String,
+}
+
+impl ImageInfo {
+    /// Gather the information about an image.
+    pub fn new(image: &NiftiImage) -> Self {
+        let header = image.header();
+        let datatype = match image.dtype() {
+            Some(dtype) => dtype.to_string(),
+            None => format!("unknown ({})", header.datatype),
+        };
+        let intensity_range = image
+            .data()
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold(None, |range, &v| match range {
+                None => Some((v, v)),
+                Some((min, max)) => Some((v.min(min), v.max(max))),
+            });
+        let descrip = String::from_utf8_lossy(&header.descrip)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+
+        ImageInfo {
+            format: format!("{:?}", image.format()),
+            shape: image.data().shape().to_vec(),
+            voxel_sizes: image.voxel_sizes(),
+            datatype,
+            tr: image.tr(),
+            xyzt_units: header.xyzt_units,
+            spatial_unit: _spatial_unit(header.xyzt_units),
+            temporal_unit: _temporal_unit(header.xyzt_units),
+            sform_code: header.sform_code,
+            qform_code: header.qform_code,
+            sform: get_affine_of_kind(header, AffineKind::Sform),
+            qform: get_affine_of_kind(header, AffineKind::Qform),
+            affine: image.affine().clone(),
+            affine_kind: image.affine_kind(),
+            orientation: axis_codes(image.affine()),
+            intensity_range,
+            descrip,
+        }
+    }
+
+    /// The information as a JSON object, with matrices as nested arrays.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "format": self.format,
+            "shape": self.shape,
+            "voxel_sizes": [
+                self.voxel_sizes.0,
+                self.voxel_sizes.1,
+                self.voxel_sizes.2,
+            ],
+            "datatype": self.datatype,
+            "tr": self.tr,
+            "xyzt_units": {
+                "code": self.xyzt_units,
+                "spatial": self.spatial_unit,
+                "temporal": self.temporal_unit,
+            },
+            "sform_code": self.sform_code,
+            "qform_code": self.qform_code,
+            "sform": _matrix_to_json(&self.sform),
+            "qform": _matrix_to_json(&self.qform),
+            "affine": _matrix_to_json(&self.affine),
+            "affine_kind": format!("{:?}", self.affine_kind).to_lowercase(),
+            "orientation": self.orientation,
+            "intensity_range": self.intensity_range.map(|(min, max)| [min, max]),
+            "descrip": self.descrip,
+        })
+    }
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (dx, dy, dz) = self.voxel_sizes;
+        writeln!(f, "format          {}", self.format)?;
+        writeln!(f, "shape           {:?}", self.shape)?;
+        writeln!(f, "voxel sizes     {} x {} x {}", dx, dy, dz)?;
+        writeln!(f, "datatype        {}", self.datatype)?;
+        match self.tr {
+            Some(tr) => writeln!(f, "TR              {} s", tr)?,
+            None => writeln!(f, "TR              -")?,
+        }
+        writeln!(
+            f,
+            "xyzt_units      {} ({}, {})",
+            self.xyzt_units, self.spatial_unit, self.temporal_unit
+        )?;
+        writeln!(f, "sform_code      {}", self.sform_code)?;
+        _write_matrix(f, &self.sform)?;
+        writeln!(f, "qform_code      {}", self.qform_code)?;
+        _write_matrix(f, &self.qform)?;
+        writeln!(f, "affine          {:?}", self.affine_kind)?;
+        _write_matrix(f, &self.affine)?;
+        writeln!(f, "orientation     {}", self.orientation)?;
+        match self.intensity_range {
+            Some((min, max)) => {
+                writeln!(f, "intensity range {} to {}", min, max)?
+            }
+            None => writeln!(f, "intensity range -")?,
+        }
+        write!(f, "descrip         {}", self.descrip)
+    }
+}
+
+fn _spatial_unit(xyzt_units: u8) -> &'static str {
+    match xyzt_units & 0x07 {
+        1 => "m",
+        2 => "mm",
+        3 => "um",
+        _ => "unknown",
+    }
+}
+
+fn _temporal_unit(xyzt_units: u8) -> &'static str {
+    match xyzt_units & 0x38 {
+        8 => "s",
+        16 => "ms",
+        24 => "us",
+        32 => "Hz",
+        40 => "ppm",
+        48 => "rad/s",
+        _ => "unknown",
+    }
+}
+
+fn _matrix_to_json(matrix: &Array2<f32>) -> Value {
+    matrix
+        .rows()
+        .into_iter()
+        .map(|row| row.to_vec())
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn _write_matrix(f: &mut fmt::Formatter, matrix: &Array2<f32>) -> fmt::Result {
+    for row in matrix.rows() {
+        write!(f, "               ")?;
+        for v in row {
+            write!(f, " {:>10.4}", v)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
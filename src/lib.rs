@@ -0,0 +1,81 @@
+//! # Overview
+//! The `nirust` crate is a collection of commonly-used high level functions
+//! for processing NIfTI images, performing statistical analyses, or extracting
+//! features of interest from them. As such, it provides a
+//! command-line-interface that can be used to quickly perform transformations
+//! or analyes, which can come in handy during data discovery. You can look up
+//! the CLI by running `nirust --help`. To further look up specific commands,
+//! you can run `--help` for each command, for example:
+//! `nirust mask-hemi --help`.
+//!
+//! # Library
+//! All functionality of the CLI is also available as a library, so that the
+//! functions can be embedded in other Rust programs. The most commonly used
+//! functions are re-exported at the crate root:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! fn main() -> nirust::Result<()> {
+//!     let image = nirust::load_img(Path::new("bold.nii.gz"))?;
+//!     let tsnr = nirust::voxelwise_tsnr(&image)?;
+//!     nirust::save_img(Path::new("tsnr.nii.gz"), &tsnr)
+//! }
+//! ```
+//!
+//! # Affine transformations
+//! Since NIfTI images give information for three different potential affines
+//! (sform, qform, and fall-back affine), whenever a function (for example
+//! resampling) requires affine transformations, the program will follow
+//! [the conventions outlined by the Python-based nibabel library](https://nipy.org/nibabel/nifti_images.html#choosing-the-image-affine):
+//!
+//! > 1. If sform_code != 0 (‘unknown’) use the sform affine; else
+//! > 2. If qform_code != 0 (‘unknown’) use the qform affine; else
+//! > 3. Use the fall-back affine.
+//!
+//! In general, nibabel has great documentation and some excellent tutorials
+//! [on the NIfTI file format and affine transformations](https://nipy.org/nibabel/tutorials.html).
+//! Therefore, if you are not familiar with these things, nibabel is a great
+//! place to start learning about data processing in the field of neuroimaging.
+//! You can also refer to the [official NIfTI file specifications for more information](https://nifti.nimh.nih.gov/pub/dist/src/niftilib/nifti1.h).
+
+pub mod analyze;
+mod codec;
+pub mod commands;
+pub mod error;
+pub mod image;
+pub mod info;
+pub mod masking;
+pub mod mgh;
+pub mod nifti2;
+pub mod orientation;
+pub mod registration;
+pub mod slice_timing;
+pub mod statistics;
+pub mod templates;
+pub mod transforms;
+
+pub use error::{NirustError, Result};
+pub use image::{
+    coord_transform, get_affine, get_affine_of_kind, load_img,
+    load_img_with_scaling, resample_3d_nifti, resample_3d_with_field,
+    resample_4d_nifti, resample_4d_with_field, resample_img, resample_to_img,
+    resample_to_img_with_field, resample_to_voxel_size, save_img,
+    save_img_with_dtype, select_affine, AffineKind, DisplacementField, Dtype,
+    DtypePolicy, Interpolation, NiftiImage, Scaling,
+};
+pub use info::ImageInfo;
+pub use masking::{
+    hemisphere_labels, mask_hemi, mask_hemi_with_labels, parcellate,
+    read_hemisphere_table,
+};
+pub use orientation::{as_closest_canonical, axis_codes, reorient};
+pub use registration::{
+    realign, register, CostFunction, RealignReference, RegistrationModel,
+};
+pub use slice_timing::{slice_time_correct, SliceInterpolation, SliceTiming};
+pub use statistics::voxelwise_tsnr;
+pub use templates::{resample_to_template, TemplateGrid, TEMPLATE_GRIDS};
+pub use transforms::{
+    apply_transform, read_displacement_field, read_transform, TransformFormat,
+};
@@ -4,97 +4,317 @@
 
 use log::{info, warn};
 use ndarray::prelude::*;
-use nifti::NiftiHeader;
-use std::f32::NAN;
+use ndarray::Zip;
+use std::fs;
 use std::option::Option::Some;
+use std::path::Path;
 
-use crate::image::{coord_transform, get_affine, resample_3d_nifti};
+use crate::error::{NirustError, Result};
+use crate::image::{
+    coord_transform, resample_3d_nifti, Interpolation, NiftiImage,
+};
+
+/// Value of the left hemisphere in hemisphere label images.
+pub const LEFT_LABEL: f32 = 1.;
+/// Value of the right hemisphere in hemisphere label images.
+pub const RIGHT_LABEL: f32 = 2.;
 
 pub fn parcellate(
-    image_data: &Array<f32, IxDyn>,
-    image_header: &NiftiHeader,
-    parcellation_data: &Array<f32, Ix3>,
-    parcellation_header: &NiftiHeader,
-) -> Array<f32, IxDyn> {
+    image: &NiftiImage,
+    parcellation: &NiftiImage,
+) -> Result<Array<f32, IxDyn>> {
+    let image_data = image.data();
     let img_shape = image_data.shape();
-    let parc_shape = parcellation_data.shape();
-
-    let x = img_shape[0];
-    let y = img_shape[1];
-    let z = img_shape[2];
-
-    let i = parc_shape[0];
-    let j = parc_shape[1];
-    let k = parc_shape[2];
+    if img_shape.len() < 3 {
+        return Err(NirustError::Shape(format!(
+            "image to parcellate must be 3D or 4D, got a {}D image",
+            img_shape.len()
+        )));
+    }
+    let parcellation_data = parcellation.data_3d()?;
 
-    if (i, j, k) != (x, y, z) {
+    if parcellation.spatial_shape() != image.spatial_shape() {
         warn!("Image and parcellation have different spatial shape");
         warn!("Resampling parcellation to image...");
 
-        let image_affine = get_affine(image_header);
-        let parc_affine = get_affine(parcellation_header);
         let parcellation_data_resampled = resample_3d_nifti(
-            parcellation_data,
-            &parc_affine,
-            &image_affine,
-            (x, y, z),
-        );
-        parcellate_any(image_data, &parcellation_data_resampled)
+            &parcellation_data,
+            parcellation.affine(),
+            image.affine(),
+            image.spatial_shape(),
+            Interpolation::Nearest,
+            0.,
+        )?;
+        parcellate_any(image_data, &parcellation_data_resampled.view())
     } else {
-        parcellate_any(image_data, parcellation_data)
+        parcellate_any(image_data, &parcellation_data)
     }
 }
 
+/// Mask the left or right hemisphere of a 3D or 4D image in place.
+///
+/// The hemisphere of every voxel is decided by its world x coordinate (see
+/// `coord_transform`): voxels left of the midline (smaller x) belong to the
+/// left hemisphere and voxels right of it to the right hemisphere. This works
+/// for any orientation of the voxel axes, including oblique images. Voxels
+/// whose center is less than half a voxel from the midline are midline
+/// voxels, which are masked together with either hemisphere unless
+/// `keep_midline` is set.
+///
+/// Parameters
+/// ----------
+/// image : 3D or 4D image, 4D images are masked volume by volume.
+///
+/// side : Hemisphere to mask, 'left' or 'right'.
+///
+/// midline_x : World x coordinate (mm) of the midline, 0 in MNI space.
+///
+/// keep_midline : Whether midline voxels are kept.
+///
+/// fill_value : Value of masked voxels, e.g. NaN or 0.
+///
+/// For images that are not in a standard space, where the midline is not a
+/// plane of constant x, use `mask_hemi_with_labels` instead.
 pub fn mask_hemi(
-    header: &NiftiHeader,
-    image_data: &mut Array<f32, IxDyn>,
+    image: &mut NiftiImage,
     side: &str,
-) {
-    let dims = image_data.shape();
-    let n_dims = dims.len();
-    let affine = get_affine(header);
-    // how many slices are there in the x direction?
-    let n_x = dims[0] as i32;
-    // left of origin (i.e. negative real-world coordinates are 'left')
-    let x_origin = _find_x_origin(n_x, &affine);
-    info!("image dimensions are {:?}", &dims);
-
-    let x_origin = x_origin as i32;
-
-    match (side, n_dims) {
-        ("left", 3) => image_data.slice_mut(s![0..x_origin, .., ..]).fill(NAN),
-        ("left", 4) => {
-            image_data.slice_mut(s![0..x_origin, .., .., ..]).fill(NAN)
+    midline_x: f32,
+    keep_midline: bool,
+    fill_value: f32,
+) -> Result<()> {
+    _check_mask_hemi_args(image, side)?;
+    let mask = _hemisphere_mask(
+        image.affine(),
+        image.spatial_shape(),
+        side,
+        midline_x,
+        keep_midline,
+    );
+    info!("Masking {} voxels", mask.iter().filter(|&&m| m).count());
+    _apply_mask(image.data_mut(), &mask, fill_value);
+    info!("Done masking the {} side of the image!", side);
+    Ok(())
+}
+
+/// Mask the left or right hemisphere of a 3D or 4D image in place, using a
+/// hemisphere label image instead of the world x coordinates of the voxels.
+///
+/// The label image is resampled to the grid of the image with nearest
+/// neighbour interpolation (see `resample_3d_nifti`), so it can be e.g. an
+/// atlas registered to the native space of the image. Voxels labelled with
+/// the side (`LEFT_LABEL` or `RIGHT_LABEL`) are masked, all other voxels are
+/// kept. A label image can be created from a parcellation with
+/// `hemisphere_labels`.
+///
+/// Parameters
+/// ----------
+/// image : 3D or 4D image, 4D images are masked volume by volume.
+///
+/// side : Hemisphere to mask, 'left' or 'right'.
+///
+/// hemispheres : 3D image with `LEFT_LABEL` for the left and `RIGHT_LABEL`
+/// for the right hemisphere.
+///
+/// fill_value : Value of masked voxels, e.g. NaN or 0.
+///
+pub fn mask_hemi_with_labels(
+    image: &mut NiftiImage,
+    side: &str,
+    hemispheres: &NiftiImage,
+    fill_value: f32,
+) -> Result<()> {
+    _check_mask_hemi_args(image, side)?;
+    let label = match side {
+        "left" => LEFT_LABEL,
+        _ => RIGHT_LABEL,
+    };
+    info!("Resampling hemisphere labels to image...");
+    let resampled = resample_3d_nifti(
+        &hemispheres.data_3d()?,
+        hemispheres.affine(),
+        image.affine(),
+        image.spatial_shape(),
+        Interpolation::Nearest,
+        0.,
+    )?;
+    let mask = resampled.mapv(|value| value.round() == label);
+    info!("Masking {} voxels", mask.iter().filter(|&&m| m).count());
+    _apply_mask(image.data_mut(), &mask, fill_value);
+    info!("Done masking the {} side of the image!", side);
+    Ok(())
+}
+
+/// Create a hemisphere label image from a parcellation, with `LEFT_LABEL`
+/// for all voxels of the left labels, `RIGHT_LABEL` for all voxels of the
+/// right labels and 0 for all other voxels.
+///
+/// Parameters
+/// ----------
+/// parcellation : 3D image with integer labels.
+///
+/// left : Labels of the parcellation in the left hemisphere.
+///
+/// right : Labels of the parcellation in the right hemisphere.
+///
+pub fn hemisphere_labels(
+    parcellation: &NiftiImage,
+    left: &[i64],
+    right: &[i64],
+) -> Result<NiftiImage> {
+    let labels = parcellation.data_3d()?.mapv(|value| {
+        let value = value.round() as i64;
+        if left.contains(&value) {
+            LEFT_LABEL
+        } else if right.contains(&value) {
+            RIGHT_LABEL
+        } else {
+            0.
         }
-        ("right", 3) => {
-            image_data.slice_mut(s![x_origin..n_x, .., ..]).fill(NAN)
+    });
+    parcellation.new_img_like(labels.into_dyn())
+}
+
+/// Read which labels of a parcellation belong to the left and the right
+/// hemisphere from a table.
+///
+/// Every line of the table contains a label and its hemisphere ('left',
+/// 'right', 'L', 'R', 'lh' or 'rh', case-insensitive), separated by tabs,
+/// spaces or a comma. Lines starting with '#', a header line and labels of
+/// other hemispheres (e.g. 'midline') are skipped.
+///
+/// Returns the left and the right labels.
+pub fn read_hemisphere_table(path: &Path) -> Result<(Vec<i64>, Vec<i64>)> {
+    let text = fs::read_to_string(path)?;
+    let (mut left, mut right) = (Vec::new(), Vec::new());
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|field| !field.is_empty())
+            .collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+        let label = match fields[0].parse::<i64>() {
+            Ok(label) => label,
+            Err(_) if number == 0 => continue,
+            Err(_) => {
+                return Err(NirustError::Format(format!(
+                    "{:?} line {}: '{}' is not an integer label",
+                    path,
+                    number + 1,
+                    fields[0]
+                )))
+            }
+        };
+        let hemisphere = fields.get(1).map(|h| h.to_lowercase());
+        match hemisphere.as_deref() {
+            Some("left" | "l" | "lh") => left.push(label),
+            Some("right" | "r" | "rh") => right.push(label),
+            Some(_) => {}
+            None => {
+                return Err(NirustError::Format(format!(
+                    "{:?} line {}: label {} has no hemisphere",
+                    path,
+                    number + 1,
+                    label
+                )))
+            }
         }
-        ("right", 4) => image_data
-            .slice_mut(s![x_origin..n_x, .., .., ..])
-            .fill(NAN),
-        _ => panic!("Error: 'side' parameter can be 'left' or 'right'!"),
     }
-    info!("Done masking the {} side of the image!", side);
+    info!(
+        "{} left and {} right labels in hemisphere table",
+        left.len(),
+        right.len()
+    );
+    Ok((left, right))
+}
+
+fn _check_mask_hemi_args(image: &NiftiImage, side: &str) -> Result<()> {
+    if side != "left" && side != "right" {
+        return Err(NirustError::Argument(format!(
+            "'side' parameter can be 'left' or 'right', got '{}'",
+            side
+        )));
+    }
+    let n_dims = image.ndim();
+    if n_dims != 3 && n_dims != 4 {
+        return Err(NirustError::Shape(format!(
+            "can only mask 3D or 4D images, got a {}D image",
+            n_dims
+        )));
+    }
+    info!("image dimensions are {:?}", image.data().shape());
+    Ok(())
+}
+
+// Voxels of a grid that belong to the given side, decided by their world x
+// coordinate.
+fn _hemisphere_mask(
+    affine: &Array2<f32>,
+    shape: (usize, usize, usize),
+    side: &str,
+    midline_x: f32,
+    keep_midline: bool,
+) -> Array3<bool> {
+    // half of the largest step in x between neighbouring voxels
+    let half_voxel = (0..3)
+        .map(|axis| affine[[0, axis]].abs())
+        .fold(0., f32::max)
+        / 2.;
+    Array3::from_shape_fn(shape, |(i, j, k)| {
+        let (x, _, _) = coord_transform(i as f32, j as f32, k as f32, affine);
+        let distance = x - midline_x;
+        if distance.abs() < half_voxel {
+            return !keep_midline;
+        }
+        match side {
+            "left" => distance < 0.,
+            _ => distance > 0.,
+        }
+    })
+}
+
+// Set the masked voxels of every volume to the fill value.
+fn _apply_mask(
+    data: &mut Array<f32, IxDyn>,
+    mask: &Array3<bool>,
+    fill_value: f32,
+) {
+    let mask = mask.view().into_dyn();
+    let volumes: Vec<ArrayViewMutD<f32>> = match data.ndim() {
+        3 => vec![data.view_mut()],
+        _ => data.axis_iter_mut(Axis(3)).collect(),
+    };
+    for volume in volumes {
+        Zip::from(volume).and(&mask).for_each(|value, &masked| {
+            if masked {
+                *value = fill_value;
+            }
+        });
+    }
 }
 
 fn parcellate_any(
     image_data: &Array<f32, IxDyn>,
-    parcellation_data: &Array<f32, Ix3>,
-) -> Array<f32, IxDyn> {
+    parcellation_data: &ArrayView3<f32>,
+) -> Result<Array<f32, IxDyn>> {
     let dims = image_data.shape().len();
     info!("Image to parcellate has {} dimensions.", dims);
     if dims == 3 {
-        _parcellate_3d(image_data, parcellation_data).into_dyn()
+        Ok(_parcellate_3d(image_data, parcellation_data).into_dyn())
     } else if dims == 4 {
-        _parcellate_4d(image_data, parcellation_data).into_dyn()
+        Ok(_parcellate_4d(image_data, parcellation_data).into_dyn())
     } else {
-        panic!("Not a 3D or 4D image!");
+        Err(NirustError::Shape(format!(
+            "image to parcellate must be 3D or 4D, got a {}D image",
+            dims
+        )))
     }
 }
 
 fn _parcellate_3d(
     image_data: &Array<f32, IxDyn>,
-    parcellation_data: &Array<f32, Ix3>,
+    parcellation_data: &ArrayView3<f32>,
 ) -> Array<f32, Ix1> {
     let n_rois = _find_max_val(parcellation_data) as i32;
     info!("{} ROIs detected in parcellation!", n_rois);
@@ -110,19 +330,19 @@ fn _parcellate_3d(
         );
         means_rois
             .slice_mut(s![roi - 1])
-            .fill(roi_data.mean().unwrap());
+            .fill(roi_data.mean().unwrap_or(f32::NAN));
     }
     means_rois
 }
 
 fn _parcellate_4d(
     image_data: &Array<f32, IxDyn>,
-    parcellation_data: &Array<f32, Ix3>,
+    parcellation_data: &ArrayView3<f32>,
 ) -> Array<f32, Ix2> {
     let n_rois = _find_max_val(parcellation_data) as i32;
     info!("{} ROIs detected in parcellation!", n_rois);
 
-    let time_dim = image_data.shape()[3] as usize;
+    let time_dim = image_data.shape()[3];
     let mut mean_timeseries =
         Array::<f32, Ix2>::zeros((time_dim, n_rois as usize));
 
@@ -145,7 +365,7 @@ fn _parcellate_4d(
     mean_timeseries
 }
 
-fn _find_max_val(array: &Array<f32, Ix3>) -> f32 {
+fn _find_max_val(array: &ArrayView3<f32>) -> f32 {
     let mut val = 0.;
     for x in array.iter() {
         if *x > val {
@@ -155,19 +375,143 @@ fn _find_max_val(array: &Array<f32, Ix3>) -> f32 {
     val
 }
 
-// find the x index at which the
-// first non-negative real world coordinate appears
-fn _find_x_origin(n_x: i32, affine: &Array2<f32>) -> f32 {
-    for x_i in 0..n_x {
-        let x_i = x_i as f32;
-        let (x_coord, _, _) = coord_transform(x_i, 0., 0., affine);
-        if x_coord < 0. {
-            continue;
-        } else {
-            return x_i;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+
+    fn _image(
+        shape: (usize, usize, usize),
+        affine: &Array2<f32>,
+    ) -> NiftiImage {
+        let data = Array::ones(shape).into_dyn();
+        let mut image = NiftiImage::new(NiftiHeader::default(), data).unwrap();
+        image.set_affine(affine);
+        image
+    }
+
+    // radiological voxel order with world x 4, 2, 0, -2 and -4 along the
+    // first axis
+    fn _las_image() -> NiftiImage {
+        let affine = arr2(&[
+            [-2., 0., 0., 4.],
+            [0., 2., 0., 0.],
+            [0., 0., 2., 0.],
+            [0., 0., 0., 1.],
+        ]);
+        _image((5, 2, 2), &affine)
+    }
+
+    // Whether the voxels along the first axis were masked (set to NaN).
+    fn _masked_x(image: &NiftiImage) -> Vec<bool> {
+        image
+            .data()
+            .slice(s![.., 0, 0])
+            .iter()
+            .map(|v| v.is_nan())
+            .collect()
+    }
+
+    #[test]
+    fn hemispheres_follow_world_x_for_flipped_axes() {
+        let mut image = _las_image();
+        mask_hemi(&mut image, "left", 0., false, f32::NAN).unwrap();
+        assert_eq!(_masked_x(&image), [false, false, true, true, true]);
+
+        let mut image = _las_image();
+        mask_hemi(&mut image, "right", 0., false, f32::NAN).unwrap();
+        assert_eq!(_masked_x(&image), [true, true, true, false, false]);
+    }
+
+    #[test]
+    fn midline_voxels_can_be_kept_and_moved() {
+        let mut image = _las_image();
+        mask_hemi(&mut image, "left", 0., true, f32::NAN).unwrap();
+        assert_eq!(_masked_x(&image), [false, false, false, true, true]);
+
+        let mut image = _las_image();
+        mask_hemi(&mut image, "left", 2., false, f32::NAN).unwrap();
+        assert_eq!(_masked_x(&image), [false, true, true, true, true]);
+
+        let mut image = _las_image();
+        mask_hemi(&mut image, "right", 2., true, f32::NAN).unwrap();
+        assert_eq!(_masked_x(&image), [true, false, false, false, false]);
+    }
+
+    #[test]
+    fn hemispheres_follow_world_x_for_oblique_axes() {
+        // voxel axes rotated by 45 degrees about z, so that x = 2 (i - j)
+        // with half a voxel of 1 mm along x
+        let affine = arr2(&[
+            [2., -2., 0., 0.],
+            [2., 2., 0., 0.],
+            [0., 0., 2., 0.],
+            [0., 0., 0., 1.],
+        ]);
+        let mut image = _image((4, 4, 1), &affine);
+        mask_hemi(&mut image, "left", 0., false, f32::NAN).unwrap();
+        for ((i, j, _), value) in image.data_3d().unwrap().indexed_iter() {
+            assert_eq!(value.is_nan(), i <= j, "voxel ({}, {})", i, j);
         }
     }
-    warn!("No origin for x could be found! Returning the highest index...");
 
-    n_x as f32
+    #[test]
+    fn hemisphere_labels_assign_parcels_to_sides() {
+        let data = array![[[0., 1.], [2., 3.]], [[4., 3.], [2., 1.]]];
+        let mut parcellation =
+            NiftiImage::new(NiftiHeader::default(), data.into_dyn()).unwrap();
+        parcellation.set_affine(&Array2::eye(4));
+        let labels = hemisphere_labels(&parcellation, &[1, 3], &[2]).unwrap();
+        let (l, r) = (LEFT_LABEL, RIGHT_LABEL);
+        assert_eq!(
+            labels.data(),
+            &array![[[0., l], [r, l]], [[0., l], [r, l]]].into_dyn()
+        );
+    }
+
+    #[test]
+    fn labels_are_resampled_to_the_image_grid() {
+        // world x of the image voxels is 0, 1, 2 and 3, which fall into the
+        // left and right voxel of the label image and outside of it
+        let mut image = _image((4, 1, 1), &Array2::eye(4));
+        let mut hemispheres = _image((2, 1, 1), &Array2::eye(4));
+        hemispheres
+            .data_mut()
+            .assign(&array![[[LEFT_LABEL]], [[RIGHT_LABEL]]].into_dyn());
+        hemispheres.set_affine(&arr2(&[
+            [2., 0., 0., -0.5],
+            [0., 1., 0., 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.],
+        ]));
+        mask_hemi_with_labels(&mut image, "right", &hemispheres, f32::NAN)
+            .unwrap();
+        assert_eq!(_masked_x(&image), [false, true, true, false]);
+    }
+
+    #[test]
+    fn hemisphere_table_accepts_common_formats() {
+        let path = std::env::temp_dir().join("nirust_masking_test_table.csv");
+        fs::write(
+            &path,
+            "label,hemisphere\n# comment\n1,left\n2\tR\n3 lh\n\n4 midline\n\
+            5 RH\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_hemisphere_table(&path).unwrap(),
+            (vec![1, 3], vec![2, 5])
+        );
+
+        fs::write(&path, "1 left\n2\n").unwrap();
+        assert!(matches!(
+            read_hemisphere_table(&path),
+            Err(NirustError::Format(_))
+        ));
+        fs::write(&path, "1 left\nsecond right\n").unwrap();
+        assert!(matches!(
+            read_hemisphere_table(&path),
+            Err(NirustError::Format(_))
+        ));
+    }
 }
@@ -0,0 +1,338 @@
+//! The `nirust::mgh` module reads and writes FreeSurfer MGH volumes (`.mgh`)
+//! and their gzip-compressed variant (`.mgz`), for example `T1.mgz` or
+//! `aparc+aseg.mgz` from a FreeSurfer subject directory.
+//!
+//! MGH files consist of a big endian 284-byte header, the image data with the
+//! first dimension fastest and an optional footer with scan parameters. The
+//! header stores the voxel sizes, the direction cosines and the world
+//! coordinates of the center of the volume, from which the vox2ras matrix is
+//! computed. The header is converted to a NIfTI-1 `NiftiHeader` with the
+//! vox2ras matrix as sform (scanner coordinates), so that MGH volumes have
+//! the same in-memory representation as all other images. See the
+//! [FreeSurfer wiki](https://surfer.nmr.mgh.harvard.edu/fswiki/FsTutorial/MghFormat)
+//! for the layout of the file.
+
+use log::{info, warn};
+use ndarray::prelude::*;
+use nifti::NiftiHeader;
+use std::path::Path;
+
+use crate::codec::{
+    encode, read_bytes, write_bytes, HeaderBuilder, HeaderFields, ParsedHeader,
+};
+use crate::error::{NirustError, Result};
+use crate::image::Dtype;
+use crate::nifti2::NIFTI1_MAX_DIM;
+
+/// Size of the MGH header in bytes, the image data start right after it.
+pub const MGH_HEADER_SIZE: usize = 284;
+
+const MGH_VERSION: i32 = 1;
+
+// direction cosines of the columns, rows and slices of a volume without
+// valid orientation information (FreeSurfer's coronal, i.e. LIA, default)
+const DEFAULT_MDC: [[f32; 3]; 3] = [[-1., 0., 0.], [0., 0., -1.], [0., 1., 0.]];
+
+/// Check whether a path points to an MGH volume, i.e. ends with `.mgh`,
+/// `.mgz` or `.mgh.gz`.
+pub fn is_mgh_path(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".mgh")
+        || name.ends_with(".mgz")
+        || name.ends_with(".mgh.gz")
+}
+
+/// Read a (possibly compressed) MGH volume.
+///
+/// Returns the header converted to NIfTI-1, with the vox2ras matrix as sform
+/// and the repetition time of the footer (if any) in `pixdim[4]`, and the
+/// values as they are stored on disk.
+pub fn read_mgh(path: &Path) -> Result<(NiftiHeader, Array<f32, IxDyn>)> {
+    let bytes = read_bytes(path)?;
+    if bytes.len() < MGH_HEADER_SIZE {
+        return Err(_invalid(path, "file is shorter than the header"));
+    }
+    let fields = HeaderFields {
+        bytes: &bytes,
+        big_endian: true,
+    };
+    let version = fields.i32(0);
+    if version != MGH_VERSION {
+        return Err(_invalid(path, &format!("unknown version {}", version)));
+    }
+
+    let mut shape: Vec<usize> = Vec::with_capacity(4);
+    for axis in 0..4 {
+        let n = fields.i32(4 + 4 * axis);
+        if n < 1 {
+            return Err(_invalid(path, "dimensions must be positive"));
+        }
+        shape.push(n as usize);
+    }
+    // single frame volumes are 3D
+    if shape[3] == 1 {
+        shape.pop();
+    }
+    let mgh_type = fields.i32(20);
+    let dtype = _dtype_from_mgh_type(mgh_type).ok_or_else(|| {
+        _invalid(path, &format!("data type {} is not supported", mgh_type))
+    })?;
+
+    let (spacing, mdc, c_ras) = if fields.i16(28) > 0 {
+        let vector = |offset: usize| {
+            [
+                fields.f32(offset),
+                fields.f32(offset + 4),
+                fields.f32(offset + 8),
+            ]
+        };
+        let mdc = [vector(42), vector(54), vector(66)];
+        (vector(30), mdc, vector(78))
+    } else {
+        warn!(
+            "{:?} has no valid orientation, assuming 1 mm voxels in coronal \
+            (LIA) orientation",
+            path
+        );
+        ([1.; 3], DEFAULT_MDC, [0.; 3])
+    };
+
+    let mut parsed = ParsedHeader {
+        header: NiftiHeader::default(),
+        shape,
+        dtype,
+        big_endian: true,
+        vox_offset: MGH_HEADER_SIZE,
+    };
+    let data = parsed.decode_data(&bytes)?;
+
+    // the optional footer starts with the repetition time in milliseconds
+    let n_bytes = data.len() * dtype.bitpix() as usize / 8;
+    let footer = MGH_HEADER_SIZE + n_bytes;
+    let tr = if bytes.len() >= footer + 4 {
+        fields.f32(footer)
+    } else {
+        0.
+    };
+
+    let dims = [parsed.shape[0], parsed.shape[1], parsed.shape[2]];
+    let affine = _vox2ras(spacing, mdc, c_ras, dims);
+    info!("vox2ras of MGH volume: {:?}", affine);
+    parsed.header = _nifti_header(&parsed.shape, dtype, spacing, tr, &affine);
+    Ok((parsed.header, data))
+}
+
+/// Write an MGH volume, compressed if the path ends with `.mgz` or `.gz`.
+///
+/// MGH volumes have no intensity scaling, so `data` must contain real-world
+/// values.
+///
+/// Parameters
+/// ----------
+/// path : Output path.
+///
+/// data : Image data with 3 or 4 dimensions.
+///
+/// dtype : Datatype of the stored values, either 'uint8', 'int16', 'int32'
+/// or 'float32'. Use `mgh_dtype` to choose one of these.
+///
+/// affine : vox2ras matrix of the volume.
+///
+/// tr : Repetition time in seconds, stored in the footer.
+///
+pub fn write_mgh(
+    path: &Path,
+    data: &Array<f32, IxDyn>,
+    dtype: Dtype,
+    affine: &Array2<f32>,
+    tr: Option<f32>,
+) -> Result<()> {
+    let shape = data.shape();
+    if shape.len() > 4 {
+        return Err(NirustError::Shape(format!(
+            "MGH volumes can have at most 4 dimensions, got {}",
+            shape.len()
+        )));
+    }
+    let mgh_type = _mgh_type(dtype).ok_or_else(|| {
+        NirustError::Argument(format!(
+            "{} voxels cannot be stored in an MGH volume",
+            dtype
+        ))
+    })?;
+    let dims: Vec<usize> = (0..4)
+        .map(|axis| shape.get(axis).copied().unwrap_or(1))
+        .collect();
+    if dims.iter().any(|&n| n > i32::MAX as usize) {
+        return Err(NirustError::Shape(format!(
+            "dimensions {:?} are too large for an MGH volume",
+            shape
+        )));
+    }
+
+    let mut builder = HeaderBuilder::new(MGH_HEADER_SIZE);
+    let mut put = |offset: usize, value: &[u8]| builder.put(offset, value);
+    put(0, &MGH_VERSION.to_be_bytes());
+    for (axis, &n) in dims.iter().enumerate() {
+        put(4 + 4 * axis, &(n as i32).to_be_bytes());
+    }
+    put(20, &mgh_type.to_be_bytes());
+    put(28, &1i16.to_be_bytes());
+
+    let (spacing, mdc, c_ras) =
+        _decompose_vox2ras(affine, [dims[0], dims[1], dims[2]]);
+    let floats = spacing.iter().chain(mdc.iter().flatten()).chain(&c_ras);
+    for (i, v) in floats.enumerate() {
+        put(30 + 4 * i, &v.to_be_bytes());
+    }
+
+    let mut bytes = builder.bytes;
+    // MGH stores the first dimension fastest, i.e. in Fortran order
+    let data_t = data.t();
+    let values = data_t.iter().map(|&v| v as f64);
+    bytes.extend(encode(values, dtype, true));
+    // footer with TR (ms), flip angle, TE, TI and field of view
+    let tr_ms = tr.unwrap_or(0.) * 1000.;
+    for v in [tr_ms, 0., 0., 0., 0.] {
+        bytes.extend_from_slice(&v.to_be_bytes());
+    }
+    info!("Saving {} MGH volume at {:?}", dtype, path);
+    write_bytes(path, &bytes)
+}
+
+/// Choose the datatype to store values in an MGH volume, which supports only
+/// 'uint8', 'int16', 'int32' and 'float32'. Integer types are widened if
+/// possible and all other types are stored as 'float32'.
+pub fn mgh_dtype(dtype: Dtype) -> Dtype {
+    match dtype {
+        Dtype::Uint8 | Dtype::Int16 | Dtype::Int32 | Dtype::Float32 => dtype,
+        Dtype::Int8 => Dtype::Int16,
+        Dtype::Uint16 => Dtype::Int32,
+        _ => {
+            warn!(
+                "MGH volumes cannot store {} voxels, storing float32 instead",
+                dtype
+            );
+            Dtype::Float32
+        }
+    }
+}
+
+fn _dtype_from_mgh_type(mgh_type: i32) -> Option<Dtype> {
+    match mgh_type {
+        0 => Some(Dtype::Uint8),
+        1 => Some(Dtype::Int32),
+        3 => Some(Dtype::Float32),
+        4 => Some(Dtype::Int16),
+        _ => None,
+    }
+}
+
+fn _mgh_type(dtype: Dtype) -> Option<i32> {
+    match dtype {
+        Dtype::Uint8 => Some(0),
+        Dtype::Int32 => Some(1),
+        Dtype::Float32 => Some(3),
+        Dtype::Int16 => Some(4),
+        _ => None,
+    }
+}
+
+// vox2ras = [Mdc * diag(spacing), P0] such that the center voxel
+// (dims / 2) is mapped to c_ras
+fn _vox2ras(
+    spacing: [f32; 3],
+    mdc: [[f32; 3]; 3],
+    c_ras: [f32; 3],
+    dims: [usize; 3],
+) -> Array2<f32> {
+    let mut affine = Array2::<f32>::eye(4);
+    for (axis, direction) in mdc.iter().enumerate() {
+        for row in 0..3 {
+            affine[[row, axis]] = direction[row] * spacing[axis];
+        }
+    }
+    for row in 0..3 {
+        let center: f32 = (0..3)
+            .map(|axis| affine[[row, axis]] * dims[axis] as f32 / 2.)
+            .sum();
+        affine[[row, 3]] = c_ras[row] - center;
+    }
+    affine
+}
+
+// inverse of `_vox2ras`: voxel sizes, direction cosines and center
+fn _decompose_vox2ras(
+    affine: &Array2<f32>,
+    dims: [usize; 3],
+) -> ([f32; 3], [[f32; 3]; 3], [f32; 3]) {
+    let mut spacing = [0f32; 3];
+    let mut mdc = DEFAULT_MDC;
+    for axis in 0..3 {
+        let column = affine.slice(s![0..3, axis]);
+        let norm = column.mapv(|v| v * v).sum().sqrt();
+        if norm > 0. {
+            spacing[axis] = norm;
+            for row in 0..3 {
+                mdc[axis][row] = column[row] / norm;
+            }
+        } else {
+            spacing[axis] = 1.;
+        }
+    }
+    let mut c_ras = [0f32; 3];
+    for (row, c) in c_ras.iter_mut().enumerate() {
+        *c = affine[[row, 3]]
+            + (0..3)
+                .map(|axis| affine[[row, axis]] * dims[axis] as f32 / 2.)
+                .sum::<f32>();
+    }
+    (spacing, mdc, c_ras)
+}
+
+fn _nifti_header(
+    shape: &[usize],
+    dtype: Dtype,
+    spacing: [f32; 3],
+    tr: f32,
+    affine: &Array2<f32>,
+) -> NiftiHeader {
+    let mut dim = [1u16; 8];
+    dim[0] = shape.len() as u16;
+    for (axis, &n) in shape.iter().enumerate() {
+        dim[axis + 1] = n.min(NIFTI1_MAX_DIM) as u16;
+    }
+    let mut pixdim = [1f32; 8];
+    pixdim[1..4].copy_from_slice(&spacing);
+    // TR is given in milliseconds, NIfTI headers store seconds
+    pixdim[4] = tr / 1000.;
+    let row = |i: usize| {
+        [
+            affine[[i, 0]],
+            affine[[i, 1]],
+            affine[[i, 2]],
+            affine[[i, 3]],
+        ]
+    };
+    NiftiHeader {
+        dim,
+        datatype: dtype.code(),
+        bitpix: dtype.bitpix(),
+        pixdim,
+        scl_slope: 1.,
+        scl_inter: 0.,
+        // millimeters and seconds
+        xyzt_units: 2 | 8,
+        sform_code: 1,
+        qform_code: 0,
+        srow_x: row(0),
+        srow_y: row(1),
+        srow_z: row(2),
+        magic: *b"n+1\0",
+        ..NiftiHeader::default()
+    }
+}
+
+fn _invalid(path: &Path, msg: &str) -> NirustError {
+    NirustError::Format(format!("{:?} is not a valid MGH file: {}", path, msg))
+}
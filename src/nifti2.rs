@@ -0,0 +1,320 @@
+//! The `nirust::nifti2` module reads and writes NIfTI-2 images, which are
+//! not supported by the `nifti` crate. Single-file images (`.nii` or
+//! `.nii.gz`) are handled here, pairs of header and image files by the
+//! `nirust::analyze` module.
+//!
+//! NIfTI-2 uses the same fields as NIfTI-1, but with 64-bit dimensions and
+//! double precision floats in a 540-byte header. The header is converted to
+//! a NIfTI-1 `NiftiHeader`, so that NIfTI-2 images have the same in-memory
+//! representation as all other images. Dimensions that exceed the NIfTI-1
+//! limit are clamped in the header, the shape of the image data stays
+//! authoritative. See the [NIfTI-2 specification](https://nifti.nimh.nih.gov/pub/dist/doc/nifti2.h)
+//! for the layout of the header.
+
+use ndarray::prelude::*;
+use nifti::NiftiHeader;
+use std::path::Path;
+
+use crate::codec::{
+    read_bytes, read_head, write_bytes, HeaderBuilder, HeaderFields,
+    ParsedHeader,
+};
+use crate::error::{NirustError, Result};
+use crate::image::Dtype;
+
+/// Size of the NIfTI-2 header in bytes.
+pub const NIFTI2_HEADER_SIZE: usize = 540;
+
+/// Largest size of a dimension that can be stored in a NIfTI-1 header.
+pub const NIFTI1_MAX_DIM: usize = i16::MAX as usize;
+
+// header size and extension flag before the image data
+const NIFTI2_VOX_OFFSET: usize = 544;
+
+/// Check whether a file is a NIfTI-2 image by looking at the size of its
+/// header.
+pub fn is_nifti2(path: &Path) -> Result<bool> {
+    let head = read_head(path, 4)?;
+    if head.len() < 4 {
+        return Ok(false);
+    }
+    let sizeof_hdr = [head[0], head[1], head[2], head[3]];
+    Ok(i32::from_le_bytes(sizeof_hdr) == NIFTI2_HEADER_SIZE as i32
+        || i32::from_be_bytes(sizeof_hdr) == NIFTI2_HEADER_SIZE as i32)
+}
+
+/// Read a single-file NIfTI-2 image.
+///
+/// Returns the header converted to NIfTI-1 and the values as they are
+/// stored on disk, i.e. without applying `scl_slope` and `scl_inter`.
+pub fn read_nifti2(path: &Path) -> Result<(NiftiHeader, Array<f32, IxDyn>)> {
+    let bytes = read_bytes(path)?;
+    let parsed = parse_nifti2_header(&bytes, path)?;
+    if &parsed.header.magic != b"n+1\0" {
+        return Err(_invalid(
+            path,
+            "header and image data are stored in separate files ('ni2'), \
+            load the pair from its .hdr or .img file",
+        ));
+    }
+    let data = parsed.decode_data(&bytes)?;
+    Ok((parsed.header, data))
+}
+
+/// Parse a NIfTI-2 header and convert it to a NIfTI-1 header.
+///
+/// The `magic` of the returned header is 'n+1' for single-file images and
+/// 'ni1' for pairs of header and image files.
+pub(crate) fn parse_nifti2_header(
+    bytes: &[u8],
+    path: &Path,
+) -> Result<ParsedHeader> {
+    if bytes.len() < NIFTI2_HEADER_SIZE {
+        return Err(_invalid(path, "file is shorter than the header"));
+    }
+    let big_endian =
+        i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            != NIFTI2_HEADER_SIZE as i32;
+    let fields = HeaderFields { bytes, big_endian };
+    let magic = match &bytes[4..8] {
+        b"n+2\0" => *b"n+1\0",
+        b"ni2\0" => *b"ni1\0",
+        _ => return Err(_invalid(path, "invalid magic code")),
+    };
+
+    let n_dims = fields.i64(16) as usize;
+    if !(1..=7).contains(&n_dims) {
+        return Err(_invalid(path, "number of dimensions must be 1 to 7"));
+    }
+    let shape: Vec<usize> = (1..=n_dims)
+        .map(|axis| fields.i64(16 + 8 * axis).max(1) as usize)
+        .collect();
+
+    let datatype = fields.i16(12);
+    let dtype = Dtype::from_code(datatype).ok_or_else(|| {
+        _invalid(path, &format!("datatype {} is not supported", datatype))
+    })?;
+
+    let mut dim = [1u16; 8];
+    dim[0] = n_dims as u16;
+    for (axis, &n) in shape.iter().enumerate() {
+        dim[axis + 1] = n.min(NIFTI1_MAX_DIM) as u16;
+    }
+    let mut pixdim = [0f32; 8];
+    for (axis, p) in pixdim.iter_mut().enumerate() {
+        *p = fields.f64(104 + 8 * axis) as f32;
+    }
+    let srow = |offset: usize| {
+        let mut row = [0f32; 4];
+        for (i, r) in row.iter_mut().enumerate() {
+            *r = fields.f64(offset + 8 * i) as f32;
+        }
+        row
+    };
+
+    let header = NiftiHeader {
+        dim,
+        intent_p1: fields.f64(80) as f32,
+        intent_p2: fields.f64(88) as f32,
+        intent_p3: fields.f64(96) as f32,
+        datatype,
+        bitpix: fields.i16(14),
+        pixdim,
+        scl_slope: fields.f64(176) as f32,
+        scl_inter: fields.f64(184) as f32,
+        cal_max: fields.f64(192) as f32,
+        cal_min: fields.f64(200) as f32,
+        slice_duration: fields.f64(208) as f32,
+        toffset: fields.f64(216) as f32,
+        slice_start: fields.i64(224) as i16,
+        slice_end: fields.i64(232) as i16,
+        descrip: bytes[240..320].to_vec(),
+        aux_file: bytes[320..344].try_into().unwrap(),
+        qform_code: fields.i32(344) as i16,
+        sform_code: fields.i32(348) as i16,
+        quatern_b: fields.f64(352) as f32,
+        quatern_c: fields.f64(360) as f32,
+        quatern_d: fields.f64(368) as f32,
+        quatern_x: fields.f64(376) as f32,
+        quatern_y: fields.f64(384) as f32,
+        quatern_z: fields.f64(392) as f32,
+        srow_x: srow(400),
+        srow_y: srow(432),
+        srow_z: srow(464),
+        slice_code: fields.i32(496) as u8,
+        xyzt_units: fields.i32(500) as u8,
+        intent_code: fields.i32(504) as i16,
+        intent_name: bytes[508..524].try_into().unwrap(),
+        dim_info: bytes[524],
+        magic,
+        ..NiftiHeader::default()
+    };
+
+    Ok(ParsedHeader {
+        header,
+        shape,
+        dtype,
+        big_endian,
+        vox_offset: fields.i64(168).max(0) as usize,
+    })
+}
+
+/// Write a single-file NIfTI-2 image in little endian byte order.
+///
+/// Parameters
+/// ----------
+/// path : Output path, the file is compressed if it ends with `.gz`.
+///
+/// header : NIfTI-1 header with the metadata of the image.
+///
+/// shape : Shape of the image data, which takes precedence over the
+/// dimensions in the header.
+///
+/// data : Image data already encoded with the datatype of the header.
+///
+pub fn write_nifti2(
+    path: &Path,
+    header: &NiftiHeader,
+    shape: &[usize],
+    data: &[u8],
+) -> Result<()> {
+    // the header is followed by an empty extension flag
+    let mut bytes = encode_nifti2_header(header, shape, false);
+    bytes.extend_from_slice(&[0; NIFTI2_VOX_OFFSET - NIFTI2_HEADER_SIZE]);
+    bytes.extend_from_slice(data);
+    write_bytes(path, &bytes)
+}
+
+/// Encode a NIfTI-1 header as little endian NIfTI-2 header, either for a
+/// single file (image data at byte 544) or for a pair of header and image
+/// files.
+pub(crate) fn encode_nifti2_header(
+    header: &NiftiHeader,
+    shape: &[usize],
+    is_pair: bool,
+) -> Vec<u8> {
+    let mut builder = HeaderBuilder::new(NIFTI2_HEADER_SIZE);
+    let mut put = |offset: usize, value: &[u8]| builder.put(offset, value);
+    let (magic, vox_offset) = if is_pair {
+        (b"ni2\0", 0)
+    } else {
+        (b"n+2\0", NIFTI2_VOX_OFFSET as i64)
+    };
+
+    put(0, &(NIFTI2_HEADER_SIZE as i32).to_le_bytes());
+    put(4, magic);
+    put(8, b"\r\n\x1a\n");
+    put(12, &header.datatype.to_le_bytes());
+    put(14, &header.bitpix.to_le_bytes());
+    put(16, &(shape.len() as i64).to_le_bytes());
+    for axis in 1..8 {
+        let n = shape.get(axis - 1).copied().unwrap_or(1);
+        put(16 + 8 * axis, &(n as i64).to_le_bytes());
+    }
+    put(80, &(header.intent_p1 as f64).to_le_bytes());
+    put(88, &(header.intent_p2 as f64).to_le_bytes());
+    put(96, &(header.intent_p3 as f64).to_le_bytes());
+    for (axis, p) in header.pixdim.iter().enumerate() {
+        put(104 + 8 * axis, &(*p as f64).to_le_bytes());
+    }
+    put(168, &vox_offset.to_le_bytes());
+    put(176, &(header.scl_slope as f64).to_le_bytes());
+    put(184, &(header.scl_inter as f64).to_le_bytes());
+    put(192, &(header.cal_max as f64).to_le_bytes());
+    put(200, &(header.cal_min as f64).to_le_bytes());
+    put(208, &(header.slice_duration as f64).to_le_bytes());
+    put(216, &(header.toffset as f64).to_le_bytes());
+    put(224, &(header.slice_start as i64).to_le_bytes());
+    put(232, &(header.slice_end as i64).to_le_bytes());
+    put(240, &header.descrip[..header.descrip.len().min(80)]);
+    put(320, &header.aux_file);
+    put(344, &(header.qform_code as i32).to_le_bytes());
+    put(348, &(header.sform_code as i32).to_le_bytes());
+    put(352, &(header.quatern_b as f64).to_le_bytes());
+    put(360, &(header.quatern_c as f64).to_le_bytes());
+    put(368, &(header.quatern_d as f64).to_le_bytes());
+    put(376, &(header.quatern_x as f64).to_le_bytes());
+    put(384, &(header.quatern_y as f64).to_le_bytes());
+    put(392, &(header.quatern_z as f64).to_le_bytes());
+    for (offset, row) in [
+        (400, header.srow_x),
+        (432, header.srow_y),
+        (464, header.srow_z),
+    ] {
+        for (i, r) in row.iter().enumerate() {
+            put(offset + 8 * i, &(*r as f64).to_le_bytes());
+        }
+    }
+    put(496, &(header.slice_code as i32).to_le_bytes());
+    put(500, &(header.xyzt_units as i32).to_le_bytes());
+    put(504, &(header.intent_code as i32).to_le_bytes());
+    put(508, &header.intent_name);
+    put(524, &[header.dim_info]);
+
+    builder.bytes
+}
+
+fn _invalid(path: &Path, msg: &str) -> NirustError {
+    NirustError::Format(format!(
+        "{:?} is not a valid NIfTI-2 file: {}",
+        path, msg
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nifti2_round_trip() {
+        let path = std::env::temp_dir().join("nirust_nifti2_test.nii");
+        let header = NiftiHeader {
+            dim: [4, 3, 2, 2, 2, 1, 1, 1],
+            datatype: Dtype::Float32.code(),
+            bitpix: 32,
+            pixdim: [1., 1.5, 2., 2.5, 0.8, 1., 1., 1.],
+            scl_slope: 2.,
+            scl_inter: -1.,
+            qform_code: 1,
+            quatern_b: 0.,
+            quatern_c: 1.,
+            quatern_d: 0.,
+            quatern_x: 7.,
+            quatern_y: -8.,
+            quatern_z: 9.,
+            sform_code: 2,
+            srow_x: [1.5, 0., 0., -7.],
+            srow_y: [0., 2., 0.1, 8.],
+            srow_z: [0., 0., 2.5, -9.],
+            ..NiftiHeader::default()
+        };
+        let values: Vec<f32> = (0..24).map(|v| v as f32 * 0.25 - 3.).collect();
+        let bytes: Vec<u8> =
+            values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        write_nifti2(&path, &header, &[3, 2, 2, 2], &bytes).unwrap();
+        assert!(is_nifti2(&path).unwrap());
+        let (read, data) = read_nifti2(&path).unwrap();
+
+        assert_eq!(read.dim, header.dim);
+        assert_eq!(read.pixdim, header.pixdim);
+        assert_eq!((read.datatype, read.bitpix), (16, 32));
+        assert_eq!((read.scl_slope, read.scl_inter), (2., -1.));
+        assert_eq!((read.qform_code, read.sform_code), (1, 2));
+        assert_eq!(
+            [read.quatern_b, read.quatern_c, read.quatern_d],
+            [0., 1., 0.]
+        );
+        assert_eq!(
+            [read.quatern_x, read.quatern_y, read.quatern_z],
+            [7., -8., 9.]
+        );
+        assert_eq!(
+            [read.srow_x, read.srow_y, read.srow_z],
+            [header.srow_x, header.srow_y, header.srow_z]
+        );
+        let expected = Array::from_shape_vec((3, 2, 2, 2).f(), values)
+            .unwrap()
+            .into_dyn();
+        assert_eq!(data, expected);
+    }
+}
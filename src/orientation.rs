@@ -0,0 +1,293 @@
+//! The `nirust::orientation` module determines the anatomical orientation of
+//! the voxel axes of an image from its affine, using the axis codes of
+//! nibabel, e.g. 'RAS' if the first voxel axis points to the right, the
+//! second to anterior and the third to superior.
+//!
+//! Images can be reoriented to other axis codes, e.g. to the closest
+//! canonical (RAS) orientation as nibabel's `as_closest_canonical` does. This
+//! only flips and permutes the voxel axes, so the data are not interpolated.
+
+use log::info;
+use ndarray::prelude::*;
+
+use crate::error::{NirustError, Result};
+use crate::image::NiftiImage;
+
+// positive and negative direction of the x, y and z world axes
+const AXIS_LABELS: [(char, char); 3] = [('R', 'L'), ('A', 'P'), ('S', 'I')];
+
+/// Find the axis codes of the three voxel axes of an affine, e.g. "RAS" or
+/// "LPI".
+///
+/// Each voxel axis is assigned to the world axis it is closest to, starting
+/// with the largest component of the affine, so that every world axis is
+/// used once even for oblique affines. Voxel axes without any extent are
+/// given the code '?'.
+///
+/// Parameters
+/// ----------
+/// affine : 4x4 affine matrix mapping voxel indices to world coordinates.
+///
+pub fn axis_codes(affine: &Array2<f32>) -> String {
+    let mut codes = ['?'; 3];
+    let mut zoomed = affine.slice(s![0..3, 0..3]).to_owned();
+    for mut column in zoomed.columns_mut() {
+        let norm = column.mapv(|v| v * v).sum().sqrt();
+        if norm > 0. {
+            column /= norm;
+        }
+    }
+    for _ in 0..3 {
+        let mut best: Option<(usize, usize, f32)> = None;
+        for ((row, col), &v) in zoomed.indexed_iter() {
+            if v != 0. && best.is_none_or(|(_, _, b)| v.abs() > b.abs()) {
+                best = Some((row, col, v));
+            }
+        }
+        let Some((row, col, v)) = best else { break };
+        let (positive, negative) = AXIS_LABELS[row];
+        codes[col] = if v > 0. { positive } else { negative };
+        zoomed.row_mut(row).fill(0.);
+        zoomed.column_mut(col).fill(0.);
+    }
+    codes.iter().collect()
+}
+
+/// Reorient an image to the closest canonical orientation, i.e. such that
+/// its axis codes are "RAS", see `reorient`.
+pub fn as_closest_canonical(image: &NiftiImage) -> Result<NiftiImage> {
+    reorient(image, "RAS")
+}
+
+/// Reorient an image to the given axis codes by flipping and permuting its
+/// voxel axes.
+///
+/// The data are not interpolated, and the affine is changed such that every
+/// voxel keeps its world coordinates. Like the affine, the slice, phase and
+/// frequency dimensions in `dim_info` and the slice timing fields of the
+/// header are updated. Axes beyond the third (e.g. time) are kept, and so
+/// is the intensity scaling of images loaded with `Scaling::Raw`.
+///
+/// Parameters
+/// ----------
+/// image : 3D or higher dimensional image to reorient.
+///
+/// codes : Target axis codes, one of 'R' or 'L', 'A' or 'P' and 'S' or 'I'
+/// in any order, e.g. "RAS", "LPS" or "LIA".
+///
+/// Returns an `Affine` error if the orientation of an axis of the image is
+/// undefined.
+pub fn reorient(image: &NiftiImage, codes: &str) -> Result<NiftiImage> {
+    let target = _parse_axis_codes(codes)?;
+    if image.ndim() < 3 {
+        return Err(NirustError::Shape(format!(
+            "can only reorient images with at least 3 dimensions, got a {}D \
+            image",
+            image.ndim()
+        )));
+    }
+    let current = axis_codes(image.affine());
+    if current.contains('?') {
+        return Err(NirustError::Affine(format!(
+            "orientation of the image is undefined ({})",
+            current
+        )));
+    }
+    info!("Reorienting image from {} to {}", current, codes);
+
+    // for every new axis, the old axis it is taken from and whether it is
+    // flipped
+    let current: Vec<char> = current.chars().collect();
+    let mapping: Vec<(usize, bool)> = target
+        .iter()
+        .map(|&code| {
+            let old = current
+                .iter()
+                .position(|&c| _world_axis(c) == _world_axis(code))
+                .unwrap();
+            (old, current[old] != code)
+        })
+        .collect();
+
+    let shape = image.data().shape().to_vec();
+    let mut axes: Vec<usize> = (0..shape.len()).collect();
+    for (new, &(old, _)) in mapping.iter().enumerate() {
+        axes[new] = old;
+    }
+    let mut data = image.data().view().permuted_axes(IxDyn(&axes));
+    // new voxel indices to old voxel indices
+    let mut to_old = Array2::<f32>::zeros((4, 4));
+    to_old[[3, 3]] = 1.;
+    for (new, &(old, flipped)) in mapping.iter().enumerate() {
+        if flipped {
+            data.invert_axis(Axis(new));
+            to_old[[old, new]] = -1.;
+            to_old[[old, 3]] = shape[old] as f32 - 1.;
+        } else {
+            to_old[[old, new]] = 1.;
+        }
+    }
+
+    let mut reoriented = image
+        .new_img_like_keep_scaling(data.as_standard_layout().into_owned())?;
+    reoriented.set_affine(&image.affine().dot(&to_old));
+    _reorient_dim_info(&mut reoriented, &mapping, &shape);
+    Ok(reoriented)
+}
+
+// Update the frequency, phase and slice dimensions of the header, and the
+// slice timing if the slice axis is flipped.
+fn _reorient_dim_info(
+    image: &mut NiftiImage,
+    mapping: &[(usize, bool)],
+    shape: &[usize],
+) {
+    let header = image.header_mut();
+    let new_dim = |dim: u8| match dim {
+        0 => 0,
+        dim => {
+            let new = mapping
+                .iter()
+                .position(|&(old, _)| old == dim as usize - 1)
+                .unwrap();
+            new as u8 + 1
+        }
+    };
+    let (frequency, phase, slice) = (
+        header.dim_info & 3,
+        (header.dim_info >> 2) & 3,
+        (header.dim_info >> 4) & 3,
+    );
+    header.dim_info =
+        new_dim(frequency) | (new_dim(phase) << 2) | (new_dim(slice) << 4);
+
+    if slice == 0 || !mapping[new_dim(slice) as usize - 1].1 {
+        return;
+    }
+    // the order of the slices within slice_start and slice_end is reversed
+    let last = shape[slice as usize - 1] as i16 - 1;
+    if header.slice_end > 0 {
+        let start = header.slice_start.max(0);
+        header.slice_start = last - header.slice_end;
+        header.slice_end = last - start;
+    }
+    header.slice_code = match header.slice_code {
+        1 => 2,
+        2 => 1,
+        3 => 4,
+        4 => 3,
+        5 => 6,
+        6 => 5,
+        code => code,
+    };
+}
+
+// Parse and validate axis codes such as "LPS".
+fn _parse_axis_codes(codes: &str) -> Result<[char; 3]> {
+    let upper: Vec<char> = codes.to_uppercase().chars().collect();
+    let valid = upper.len() == 3
+        && upper.iter().all(|&c| _world_axis(c).is_some())
+        && (0..3).all(|axis| {
+            upper
+                .iter()
+                .filter(|&&c| _world_axis(c) == Some(axis))
+                .count()
+                == 1
+        });
+    if !valid {
+        return Err(NirustError::Argument(format!(
+            "invalid axis codes '{}', expected one of 'R' or 'L', 'A' or 'P' \
+            and 'S' or 'I' each, e.g. 'RAS' or 'LPS'",
+            codes
+        )));
+    }
+    Ok([upper[0], upper[1], upper[2]])
+}
+
+// World axis (0 for x, 1 for y, 2 for z) of an axis code.
+fn _world_axis(code: char) -> Option<usize> {
+    AXIS_LABELS
+        .iter()
+        .position(|&(positive, negative)| code == positive || code == negative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+
+    fn _image(affine: &Array2<f32>) -> NiftiImage {
+        let data = Array::from_shape_fn((3, 4, 5), |(i, j, k)| {
+            (i * 100 + j * 10 + k) as f32
+        });
+        let mut image =
+            NiftiImage::new(NiftiHeader::default(), data.into_dyn()).unwrap();
+        image.set_affine(affine);
+        image
+    }
+
+    #[test]
+    fn axis_codes_of_common_affines() {
+        let ras = Array2::from_diag(&array![2., 3., 4., 1.]);
+        assert_eq!(axis_codes(&ras), "RAS");
+        let las = Array2::from_diag(&array![-2., 3., 4., 1.]);
+        assert_eq!(axis_codes(&las), "LAS");
+        let permuted = arr2(&[
+            [0., 0., 2., 0.],
+            [3., 0., 0., 0.],
+            [0., -4., 0., 0.],
+            [0., 0., 0., 1.],
+        ]);
+        assert_eq!(axis_codes(&permuted), "AIR");
+    }
+
+    #[test]
+    fn reorienting_back_and_forth_restores_the_image() {
+        let affine = arr2(&[
+            [2., 0., 0., -10.],
+            [0., 3., 0., 20.],
+            [0., 0., 4., -30.],
+            [0., 0., 0., 1.],
+        ]);
+        let image = _image(&affine);
+        let lps = reorient(&image, "LPS").unwrap();
+        assert_eq!(axis_codes(lps.affine()), "LPS");
+        assert_eq!(lps.data().shape(), &[3, 4, 5]);
+        // the x and y axes are flipped, so voxels keep their world
+        // coordinates
+        assert_eq!(lps.data()[[0, 0, 1]], image.data()[[2, 3, 1]]);
+        assert_eq!(
+            lps.affine().dot(&array![0., 0., 1., 1.]),
+            affine.dot(&array![2., 3., 1., 1.])
+        );
+
+        let ras = reorient(&lps, "RAS").unwrap();
+        assert_eq!(ras.data(), image.data());
+        assert_eq!(ras.affine(), image.affine());
+    }
+
+    #[test]
+    fn reorienting_keeps_the_intensity_scaling() {
+        let mut image = _image(&Array2::from_diag(&array![-2., 3., 4., 1.]));
+        let header = image.header_mut();
+        header.scl_slope = 0.5;
+        header.scl_inter = 10.;
+        let reoriented = reorient(&image, "RAS").unwrap();
+        assert_eq!(reoriented.intensity_scaling(), Some((0.5, 10.)));
+        assert_eq!(reoriented.data()[[0, 1, 2]], image.data()[[2, 1, 2]]);
+    }
+
+    #[test]
+    fn canonical_images_are_unchanged() {
+        let affine = arr2(&[
+            [2., 0., 0., -10.],
+            [0., 3., 0., 20.],
+            [0., 0., 4., -30.],
+            [0., 0., 0., 1.],
+        ]);
+        let image = _image(&affine);
+        let canonical = as_closest_canonical(&image).unwrap();
+        assert_eq!(canonical.data(), image.data());
+        assert_eq!(canonical.affine(), image.affine());
+    }
+}
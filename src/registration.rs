@@ -0,0 +1,702 @@
+//! The `nirust::registration` module estimates rigid (6 parameters) and
+//! affine (12 parameters) transforms between two 3D images by optimizing an
+//! intensity-based cost function on a multi-resolution pyramid, e.g. for
+//! quick coregistrations of a mean EPI to a T1.
+//!
+//! The estimated transforms follow the convention of `nirust::transforms`:
+//! they map the RAS world coordinates of the reference image to those of the
+//! moving image, and can be applied with `apply_transform`. The same rigid
+//! registration is used by `realign` to correct head motion in 4D series.
+
+use log::info;
+use ndarray::parallel::prelude::*;
+use ndarray::prelude::*;
+use ndarray::Zip;
+use ndarray_linalg::solve::Inverse;
+use std::str::FromStr;
+
+use crate::error::{NirustError, Result};
+use crate::image::{
+    resample_3d_nifti, resample_to_voxel_size, Interpolation, NiftiImage,
+};
+
+// number of intensity bins of the joint histogram for mutual information
+const HISTOGRAM_BINS: usize = 32;
+// radius (mm) used to convert translation steps into rotation, scaling and
+// shearing steps of a similar displacement at the edge of the brain
+const BRAIN_RADIUS: f64 = 50.;
+// how often the steps of the optimizer are halved on each pyramid level
+const STEP_HALVINGS: usize = 5;
+// upper bound of cost function evaluations on each pyramid level
+const MAX_EVALUATIONS: usize = 2000;
+
+/// Transformation models for registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegistrationModel {
+    /// Rigid-body transform with 3 translations and 3 rotations, e.g. for
+    /// images of the same subject.
+    #[default]
+    Rigid,
+    /// Affine transform with 3 translations, 3 rotations, 3 scalings and 3
+    /// shears.
+    Affine,
+}
+
+impl RegistrationModel {
+    /// Number of parameters (degrees of freedom) of the model.
+    pub fn n_parameters(self) -> usize {
+        match self {
+            RegistrationModel::Rigid => 6,
+            RegistrationModel::Affine => 12,
+        }
+    }
+}
+
+impl FromStr for RegistrationModel {
+    type Err = String;
+
+    /// Parse "rigid" (or "6") or "affine" (or "12").
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rigid" | "6" => Ok(RegistrationModel::Rigid),
+            "affine" | "12" => Ok(RegistrationModel::Affine),
+            _ => Err(format!(
+                "unknown registration model '{}', expected 'rigid' (6) or \
+                'affine' (12)",
+                s
+            )),
+        }
+    }
+}
+
+/// Cost functions for registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CostFunction {
+    /// Normalized (Pearson) correlation of the intensities, for images with
+    /// the same contrast.
+    NormalizedCorrelation,
+    /// Normalized mutual information of the intensities (Studholme et al.,
+    /// 1999), for images with different contrasts such as an EPI and a T1.
+    #[default]
+    MutualInformation,
+}
+
+impl FromStr for CostFunction {
+    type Err = String;
+
+    /// Parse "nc" (or "correlation") or "mi" (or "mutualinfo").
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "nc" | "correlation" => Ok(CostFunction::NormalizedCorrelation),
+            "mi" | "mutualinfo" => Ok(CostFunction::MutualInformation),
+            _ => Err(format!(
+                "unknown cost function '{}', expected 'nc' or 'mi'",
+                s
+            )),
+        }
+    }
+}
+
+/// Estimate the transform that aligns a moving image to a reference image.
+///
+/// Both images are smoothed and resampled to the voxel size of each level of
+/// the pyramid, from coarse to fine, and the parameters of the transform are
+/// optimized with a pattern search on each level, starting from the result
+/// of the previous level. The first level starts from the identity, i.e. the
+/// images are assumed to be roughly aligned in world space, as images of the
+/// same session are. Rotations, scalings and shears are about the center of
+/// the reference image.
+///
+/// Parameters
+/// ----------
+/// moving : 3D image to be aligned.
+///
+/// reference : 3D image the moving image is aligned to.
+///
+/// model : Rigid (6 parameters) or affine (12 parameters) transform.
+///
+/// cost : Cost function measuring how well the images are aligned.
+///
+/// levels : Voxel sizes (mm) of the pyramid levels, from coarse to fine,
+/// e.g. `[8., 4., 2.]`. Levels finer than the images use their voxel size.
+///
+/// Returns the 4x4 affine mapping RAS world coordinates of `reference` to
+/// those of `moving`.
+pub fn register(
+    moving: &NiftiImage,
+    reference: &NiftiImage,
+    model: RegistrationModel,
+    cost: CostFunction,
+    levels: &[f32],
+) -> Result<Array2<f32>> {
+    _check_levels(levels)?;
+    let center = _grid_center(reference);
+    let parameters = _optimize(
+        &_pyramid(moving, levels)?,
+        &_pyramid(reference, levels)?,
+        model,
+        cost,
+        center,
+    );
+    Ok(_parameters_to_affine(&parameters, center))
+}
+
+/// Reference volume for the realignment of a 4D series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RealignReference {
+    /// The first volume of the series.
+    First,
+    /// The mean of all volumes, which has a higher SNR than any single
+    /// volume.
+    #[default]
+    Mean,
+    /// The volume with the given (zero-based) index.
+    Index(usize),
+}
+
+impl FromStr for RealignReference {
+    type Err = String;
+
+    /// Parse "first", "mean" or the index of a volume, e.g. "10".
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(RealignReference::First),
+            "mean" => Ok(RealignReference::Mean),
+            _ => s.parse().map(RealignReference::Index).map_err(|_| {
+                format!(
+                    "unknown reference volume '{}', expected 'first', \
+                    'mean' or the index of a volume",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// Correct head motion in a 4D series by aligning every volume to a
+/// reference volume with a rigid registration, see `register`.
+///
+/// The volumes are registered and resampled in parallel. Voxels that move
+/// out of the field of view are set to 0.
+///
+/// Parameters
+/// ----------
+/// image : 4D series, e.g. a BOLD run.
+///
+/// reference : Volume to which all volumes are aligned.
+///
+/// cost : Cost function, normalized correlation is usually appropriate
+/// since all volumes have the same contrast.
+///
+/// levels : Voxel sizes (mm) of the pyramid levels, from coarse to fine.
+///
+/// interpolation : How to obtain values between voxels when resampling.
+///
+/// Returns the realigned series and the motion parameters of each volume
+/// relative to the reference as an array of shape (volumes, 6): translations
+/// (mm) along and rotations (radians) about the x, y and z axes, about the
+/// center of the grid.
+pub fn realign(
+    image: &NiftiImage,
+    reference: RealignReference,
+    cost: CostFunction,
+    levels: &[f32],
+    interpolation: Interpolation,
+) -> Result<(NiftiImage, Array2<f32>)> {
+    _check_levels(levels)?;
+    if image.ndim() != 4 {
+        return Err(NirustError::Shape(format!(
+            "can only realign 4D images, got a {}D image",
+            image.ndim()
+        )));
+    }
+    let data = image.data().view().into_dimensionality::<Ix4>().unwrap();
+    let n_volumes = data.shape()[3];
+    let reference_data = match reference {
+        RealignReference::First => data.index_axis(Axis(3), 0).to_owned(),
+        RealignReference::Mean => data.mean_axis(Axis(3)).unwrap(),
+        RealignReference::Index(index) if index < n_volumes => {
+            data.index_axis(Axis(3), index).to_owned()
+        }
+        RealignReference::Index(index) => {
+            return Err(NirustError::Argument(format!(
+                "reference volume {} does not exist, the image has {} volumes",
+                index, n_volumes
+            )))
+        }
+    };
+    let reference_image = image.new_img_like(reference_data.into_dyn())?;
+    let reference_pyramid = _pyramid(&reference_image, levels)?;
+    let center = _grid_center(&reference_image);
+    let affine = image.affine();
+    info!(
+        "Realigning {} volumes to the {:?} volume",
+        n_volumes, reference
+    );
+
+    let mut realigned = Array4::<f32>::zeros(data.raw_dim());
+    let parameters = realigned
+        .axis_iter_mut(Axis(3))
+        .into_par_iter()
+        .zip(data.axis_iter(Axis(3)).into_par_iter())
+        .map(|(mut target, volume)| {
+            let volume_image =
+                image.new_img_like(volume.to_owned().into_dyn())?;
+            let parameters = _optimize(
+                &_pyramid(&volume_image, levels)?,
+                &reference_pyramid,
+                RegistrationModel::Rigid,
+                cost,
+                center,
+            );
+            let transform = _parameters_to_affine(&parameters, center);
+            let moved_affine = transform
+                .inv()
+                .map_err(|e| {
+                    NirustError::Affine(format!(
+                        "transform is singular ({})",
+                        e
+                    ))
+                })?
+                .dot(affine);
+            target.assign(&resample_3d_nifti(
+                &volume,
+                &moved_affine,
+                affine,
+                volume.dim(),
+                interpolation,
+                0.,
+            )?);
+            Ok(parameters)
+        })
+        .collect::<Result<Vec<Vec<f64>>>>()?;
+
+    let motion = Array2::from_shape_fn((n_volumes, 6), |(volume, p)| {
+        parameters[volume][p] as f32
+    });
+    Ok((image.new_img_like(realigned.into_dyn())?, motion))
+}
+
+fn _check_levels(levels: &[f32]) -> Result<()> {
+    if levels.is_empty() || levels.iter().any(|v| !(v.is_finite() && *v > 0.)) {
+        return Err(NirustError::Argument(format!(
+            "pyramid levels must be positive voxel sizes, got {:?}",
+            levels
+        )));
+    }
+    Ok(())
+}
+
+// Optimize the parameters of a transform level by level, see `register`.
+fn _optimize(
+    moving: &[PyramidImage],
+    reference: &[PyramidImage],
+    model: RegistrationModel,
+    cost: CostFunction,
+    center: [f64; 3],
+) -> Vec<f64> {
+    let n_parameters = model.n_parameters();
+    let mut parameters = vec![0.; 12];
+    for (moving, reference) in moving.iter().zip(reference) {
+        info!(
+            "Registering at {} mm, reference grid {:?}",
+            reference.voxel_size,
+            reference.data.dim()
+        );
+        let evaluate = |p: &[f64]| {
+            let mut all = parameters.clone();
+            all[..n_parameters].copy_from_slice(p);
+            _cost(
+                moving,
+                reference,
+                cost,
+                &_parameters_to_affine(&all, center),
+            )
+        };
+        let steps = _initial_steps(reference.voxel_size as f64);
+        let (optimum, value) = _pattern_search(
+            evaluate,
+            &parameters[..n_parameters],
+            &steps[..n_parameters],
+        );
+        info!("Cost {:.6} with parameters {:?}", value, optimum);
+        parameters[..n_parameters].copy_from_slice(&optimum);
+    }
+    parameters
+}
+
+// An image at the resolution of a pyramid level, with its intensity range
+// for the joint histogram.
+struct PyramidImage {
+    voxel_size: f32,
+    data: Array3<f32>,
+    affine: Array2<f32>,
+    range: (f32, f32),
+}
+
+fn _pyramid(image: &NiftiImage, levels: &[f32]) -> Result<Vec<PyramidImage>> {
+    levels
+        .iter()
+        .map(|&voxel_size| {
+            let (data, affine) = _pyramid_image(image, voxel_size)?;
+            Ok(PyramidImage {
+                voxel_size,
+                range: _range(&data),
+                data,
+                affine,
+            })
+        })
+        .collect()
+}
+
+// Cost of a transform (lower is better): the moving image is resampled onto
+// the reference grid, and only voxels inside both images count.
+fn _cost(
+    moving: &PyramidImage,
+    reference: &PyramidImage,
+    cost: CostFunction,
+    transform: &Array2<f32>,
+) -> f64 {
+    let Ok(transform_inv) = transform.inv() else {
+        return f64::INFINITY;
+    };
+    let resampled = resample_3d_nifti(
+        &moving.data,
+        &transform_inv.dot(&moving.affine),
+        &reference.affine,
+        reference.data.dim(),
+        Interpolation::Trilinear,
+        f32::NAN,
+    );
+    let Ok(resampled) = resampled else {
+        return f64::INFINITY;
+    };
+    match cost {
+        CostFunction::NormalizedCorrelation => {
+            -_correlation(&reference.data, &resampled)
+        }
+        CostFunction::MutualInformation => -_mutual_information(
+            &reference.data,
+            &resampled,
+            reference.range,
+            moving.range,
+        ),
+    }
+}
+
+// Smooth an image and resample it to a voxel size, keeping the voxel size of
+// axes that are already coarser. Non-finite values are set to zero.
+fn _pyramid_image(
+    image: &NiftiImage,
+    voxel_size: f32,
+) -> Result<(Array3<f32>, Array2<f32>)> {
+    let data = image
+        .data_3d()?
+        .mapv(|v| if v.is_finite() { v } else { 0. });
+    let zooms = [0, 1, 2].map(|axis| {
+        image
+            .affine()
+            .slice(s![0..3, axis])
+            .mapv(|v| v * v)
+            .sum()
+            .sqrt()
+    });
+    if zooms.iter().all(|zoom| *zoom >= voxel_size) {
+        return Ok((data, image.affine().clone()));
+    }
+    // Gaussian kernels that reduce the resolution to the voxel size
+    let sizes = zooms.map(|zoom| zoom.max(voxel_size));
+    let sigmas = [0, 1, 2].map(|axis| {
+        (sizes[axis].powi(2) - zooms[axis].powi(2)).sqrt()
+            / (2. * (2. * std::f32::consts::LN_2).sqrt())
+            / zooms[axis]
+    });
+    let smoothed = image.new_img_like(_smooth(data, sigmas).into_dyn())?;
+    let resampled = resample_to_voxel_size(
+        &smoothed,
+        (sizes[0], sizes[1], sizes[2]),
+        Interpolation::Trilinear,
+        0.,
+    )?;
+    Ok((resampled.data_3d()?.to_owned(), resampled.affine().clone()))
+}
+
+// Separable Gaussian smoothing with the given standard deviations (voxels),
+// with edge values repeated beyond the borders.
+fn _smooth(mut data: Array3<f32>, sigmas: [f32; 3]) -> Array3<f32> {
+    for (axis, sigma) in sigmas.into_iter().enumerate() {
+        if sigma < 0.1 {
+            continue;
+        }
+        let radius = (3. * sigma).ceil() as isize;
+        let kernel: Vec<f32> = (-radius..=radius)
+            .map(|x| (-0.5 * (x as f32 / sigma).powi(2)).exp())
+            .collect();
+        let total: f32 = kernel.iter().sum();
+        for mut lane in data.lanes_mut(Axis(axis)) {
+            let values = lane.to_vec();
+            let last = values.len() as isize - 1;
+            for (i, value) in lane.iter_mut().enumerate() {
+                *value = kernel
+                    .iter()
+                    .zip(-radius..=radius)
+                    .map(|(w, offset)| {
+                        let j = (i as isize + offset).clamp(0, last);
+                        w * values[j as usize]
+                    })
+                    .sum::<f32>()
+                    / total;
+            }
+        }
+    }
+    data
+}
+
+fn _range(data: &Array3<f32>) -> (f32, f32) {
+    data.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| {
+            (min.min(v), max.max(v))
+        })
+}
+
+// Pearson correlation of the voxels where both arrays are finite.
+fn _correlation(a: &Array3<f32>, b: &Array3<f32>) -> f64 {
+    let (n, sa, sb, saa, sbb, sab) = Zip::from(a).and(b).fold(
+        (0., 0., 0., 0., 0., 0.),
+        |(n, sa, sb, saa, sbb, sab), &a, &b| {
+            if !(a.is_finite() && b.is_finite()) {
+                return (n, sa, sb, saa, sbb, sab);
+            }
+            let (a, b) = (a as f64, b as f64);
+            (
+                n + 1.,
+                sa + a,
+                sb + b,
+                saa + a * a,
+                sbb + b * b,
+                sab + a * b,
+            )
+        },
+    );
+    let var_a = saa - sa * sa / n;
+    let var_b = sbb - sb * sb / n;
+    if n < 2. || var_a <= 0. || var_b <= 0. {
+        return 0.;
+    }
+    (sab - sa * sb / n) / (var_a * var_b).sqrt()
+}
+
+// Normalized mutual information (H(a) + H(b)) / H(a, b) of the joint
+// histogram of the voxels where both arrays are finite, which unlike mutual
+// information does not favour transforms that reduce the overlap.
+fn _mutual_information(
+    a: &Array3<f32>,
+    b: &Array3<f32>,
+    a_range: (f32, f32),
+    b_range: (f32, f32),
+) -> f64 {
+    // without an intensity range (constant images or no overlap) there is
+    // no histogram, 0 is below the NMI of any overlap and thus the worst cost
+    let has_range =
+        |(min, max): (f32, f32)| (max - min).is_finite() && max > min;
+    if !has_range(a_range) || !has_range(b_range) {
+        return 0.;
+    }
+    // each value is split linearly between the two closest bins, which
+    // smooths the cost function compared to counting whole voxels
+    let bins = |v: f32, (min, max): (f32, f32)| {
+        let x = ((v - min) / (max - min) * (HISTOGRAM_BINS - 1) as f32)
+            .clamp(0., (HISTOGRAM_BINS - 1) as f32);
+        let lower = (x as usize).min(HISTOGRAM_BINS - 2);
+        let weight = (x - lower as f32) as f64;
+        [(lower, 1. - weight), (lower + 1, weight)]
+    };
+    let mut joint = Array2::<f64>::zeros((HISTOGRAM_BINS, HISTOGRAM_BINS));
+    Zip::from(a).and(b).for_each(|&a, &b| {
+        if a.is_finite() && b.is_finite() {
+            for (i, wa) in bins(a, a_range) {
+                for (j, wb) in bins(b, b_range) {
+                    joint[[i, j]] += wa * wb;
+                }
+            }
+        }
+    });
+    let n = joint.sum();
+    if n == 0. {
+        return 0.;
+    }
+    joint /= n;
+    let joint_entropy = _entropy(&joint);
+    if joint_entropy == 0. {
+        return 0.;
+    }
+    (_entropy(&joint.sum_axis(Axis(1))) + _entropy(&joint.sum_axis(Axis(0))))
+        / joint_entropy
+}
+
+// Shannon entropy (nats) of a probability distribution.
+fn _entropy<'a>(p: impl IntoIterator<Item = &'a f64>) -> f64 {
+    -p.into_iter()
+        .filter(|p| **p > 0.)
+        .map(|p| p * p.ln())
+        .sum::<f64>()
+}
+
+// Minimize `cost` with a pattern search: each parameter is moved by its step
+// in both directions while this lowers the cost, and all steps are halved
+// once no move lowers it anymore.
+fn _pattern_search<F: Fn(&[f64]) -> f64>(
+    cost: F,
+    start: &[f64],
+    steps: &[f64],
+) -> (Vec<f64>, f64) {
+    let mut parameters = start.to_vec();
+    let mut steps = steps.to_vec();
+    let mut best = cost(&parameters);
+    let mut evaluations = 1;
+    for _ in 0..=STEP_HALVINGS {
+        let mut improved = true;
+        while improved && evaluations < MAX_EVALUATIONS {
+            improved = false;
+            for p in 0..parameters.len() {
+                for direction in [1., -1.] {
+                    let mut candidate = parameters.clone();
+                    candidate[p] += direction * steps[p];
+                    let value = cost(&candidate);
+                    evaluations += 1;
+                    if value < best {
+                        best = value;
+                        parameters = candidate;
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+        }
+        steps.iter_mut().for_each(|step| *step /= 2.);
+    }
+    (parameters, best)
+}
+
+// Initial steps of translations (mm), rotations (rad), scalings and shears,
+// corresponding to a displacement of about one voxel of the pyramid level.
+fn _initial_steps(voxel_size: f64) -> [f64; 12] {
+    let relative = voxel_size / BRAIN_RADIUS;
+    let mut steps = [relative; 12];
+    steps[..3].fill(voxel_size);
+    steps
+}
+
+// World coordinates of the center of the grid of an image.
+fn _grid_center(image: &NiftiImage) -> [f64; 3] {
+    let (nx, ny, nz) = image.spatial_shape();
+    let voxel = array![
+        (nx as f32 - 1.) / 2.,
+        (ny as f32 - 1.) / 2.,
+        (nz as f32 - 1.) / 2.,
+        1.
+    ];
+    let center = image.affine().dot(&voxel);
+    [center[0] as f64, center[1] as f64, center[2] as f64]
+}
+
+// Affine of the parameters: translations, rotations about the x, y and z
+// axes, scalings (relative to 1) and shears, about `center`.
+fn _parameters_to_affine(parameters: &[f64], center: [f64; 3]) -> Array2<f32> {
+    let p = parameters;
+    let (sx, cx) = p[3].sin_cos();
+    let (sy, cy) = p[4].sin_cos();
+    let (sz, cz) = p[5].sin_cos();
+    let rx = arr2(&[[1., 0., 0.], [0., cx, -sx], [0., sx, cx]]);
+    let ry = arr2(&[[cy, 0., sy], [0., 1., 0.], [-sy, 0., cy]]);
+    let rz = arr2(&[[cz, -sz, 0.], [sz, cz, 0.], [0., 0., 1.]]);
+    let scaling = Array2::from_diag(&array![1. + p[6], 1. + p[7], 1. + p[8]]);
+    let shear = arr2(&[[1., p[9], p[10]], [0., 1., p[11]], [0., 0., 1.]]);
+    let linear = rz.dot(&ry).dot(&rx).dot(&scaling).dot(&shear);
+
+    // y = L (x - c) + c + t
+    let center = arr1(&center);
+    let offset = &center - &linear.dot(&center) + arr1(&p[..3]);
+    let mut affine = Array2::<f32>::eye(4);
+    affine
+        .slice_mut(s![0..3, 0..3])
+        .assign(&linear.mapv(|v| v as f32));
+    affine
+        .slice_mut(s![0..3, 3])
+        .assign(&offset.mapv(|v| v as f32));
+    affine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+
+    // Three gaussian blobs, which fix all rotations, on a 2 mm grid centered
+    // on the origin, shifted by `shift` (mm) in world space.
+    fn _blobs(shift: [f32; 3]) -> NiftiImage {
+        let data = Array::from_shape_fn((24, 24, 24), |(i, j, k)| {
+            let world = [i, j, k].map(|v| v as f32 * 2. - 23.);
+            let blob = |center: [f32; 3], sigma: f32, height: f32| {
+                let distance: f32 = (0..3)
+                    .map(|axis| {
+                        (world[axis] - center[axis] - shift[axis]).powi(2)
+                    })
+                    .sum();
+                height * (-distance / (2. * sigma * sigma)).exp()
+            };
+            blob([0., 0., 0.], 8., 100.)
+                + blob([8., -6., 4.], 4., 60.)
+                + blob([-6., 8., -4.], 3., 80.)
+        });
+        let header = NiftiHeader {
+            sform_code: 1,
+            srow_x: [2., 0., 0., -23.],
+            srow_y: [0., 2., 0., -23.],
+            srow_z: [0., 0., 2., -23.],
+            pixdim: [1., 2., 2., 2., 1., 1., 1., 1.],
+            ..NiftiHeader::default()
+        };
+        NiftiImage::new(header, data.into_dyn()).unwrap()
+    }
+
+    #[test]
+    fn register_recovers_a_translation() {
+        let reference = _blobs([0., 0., 0.]);
+        let moving = _blobs([4., -2., 2.]);
+        let transform = register(
+            &moving,
+            &reference,
+            RegistrationModel::Rigid,
+            CostFunction::NormalizedCorrelation,
+            &[4., 2.],
+        )
+        .unwrap();
+        let rotation = transform.slice(s![0..3, 0..3]);
+        for (a, b) in rotation.iter().zip(Array2::<f32>::eye(3).iter()) {
+            assert!((a - b).abs() < 0.01, "{}", transform);
+        }
+        let translation = transform.slice(s![0..3, 3]);
+        for (a, b) in translation.iter().zip([4., -2., 2.].iter()) {
+            assert!((a - b).abs() < 0.25, "{}", transform);
+        }
+    }
+
+    #[test]
+    fn mutual_information_is_highest_for_the_same_image() {
+        let image = _blobs([0., 0., 0.]).data_3d().unwrap().to_owned();
+        // deterministic pseudo-random noise (xorshift)
+        let mut state = 0x2545f491u32;
+        let noise = image.mapv(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32
+        });
+        let range = _range(&image);
+        let same = _mutual_information(&image, &image, range, range);
+        let different =
+            _mutual_information(&image, &noise, range, _range(&noise));
+        assert!(same > different, "{} <= {}", same, different);
+    }
+}
@@ -0,0 +1,461 @@
+//! The `nirust::slice_timing` module corrects 4D images for the different
+//! acquisition times of their slices. The time series of every slice is
+//! interpolated to a common reference time within the repetition time, either
+//! linearly or with sinc interpolation, which is implemented as a phase shift
+//! in the Fourier domain (as in SPM).
+//!
+//! The acquisition times of the slices can be read from the `SliceTiming`
+//! field of a BIDS JSON sidecar, which also describes multiband acquisitions,
+//! or derived from the `slice_code` and `slice_duration` fields of the NIfTI
+//! header.
+
+use log::{info, warn};
+use ndarray::prelude::*;
+use ndarray::Zip;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{NirustError, Result};
+use crate::image::NiftiImage;
+
+/// Interpolation of the time series of a slice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SliceInterpolation {
+    /// Linear interpolation between neighbouring volumes.
+    Linear,
+    /// Sinc interpolation by a phase shift in the Fourier domain. The time
+    /// series is mirrored before the transform to avoid wrap-around effects.
+    #[default]
+    Sinc,
+}
+
+impl FromStr for SliceInterpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(SliceInterpolation::Linear),
+            "sinc" | "fft" => Ok(SliceInterpolation::Sinc),
+            _ => Err(format!(
+                "unknown slice interpolation '{}', expected 'linear' or 'sinc'",
+                s
+            )),
+        }
+    }
+}
+
+/// Acquisition times of the slices of a 4D image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceTiming {
+    /// Acquisition time (seconds) of every slice relative to the start of
+    /// the volume. Slices with a time of NaN are not corrected.
+    pub times: Vec<f32>,
+    /// Spatial axis (0, 1 or 2) along which the slices were acquired.
+    pub axis: usize,
+}
+
+impl SliceTiming {
+    /// Read the slice timing from the `SliceTiming` field of a BIDS JSON
+    /// sidecar.
+    ///
+    /// The slice axis is taken from `SliceEncodingDirection` ("i", "j" or
+    /// "k", the default). If the direction is negative (e.g. "k-"), the
+    /// first time belongs to the last slice, as defined by BIDS.
+    pub fn from_json(path: &Path) -> Result<SliceTiming> {
+        let json: Value = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| {
+                NirustError::Format(format!(
+                    "could not parse {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        let times = json["SliceTiming"]
+            .as_array()
+            .ok_or_else(|| {
+                NirustError::Format(format!(
+                    "{} has no SliceTiming array",
+                    path.display()
+                ))
+            })?
+            .iter()
+            .map(|time| time.as_f64().map(|time| time as f32))
+            .collect::<Option<Vec<f32>>>()
+            .ok_or_else(|| {
+                NirustError::Format(format!(
+                    "SliceTiming in {} must only contain numbers",
+                    path.display()
+                ))
+            })?;
+
+        let direction = json["SliceEncodingDirection"].as_str().unwrap_or("k");
+        let (axis, reversed) = match direction {
+            "i" => (0, false),
+            "j" => (1, false),
+            "k" => (2, false),
+            "i-" => (0, true),
+            "j-" => (1, true),
+            "k-" => (2, true),
+            _ => {
+                return Err(NirustError::Format(format!(
+                    "unknown SliceEncodingDirection '{}' in {}",
+                    direction,
+                    path.display()
+                )))
+            }
+        };
+        let times = match reversed {
+            true => times.into_iter().rev().collect(),
+            false => times,
+        };
+        Ok(SliceTiming { times, axis })
+    }
+
+    /// Derive the slice timing from the `slice_code`, `slice_duration`,
+    /// `slice_start` and `slice_end` fields of the header, and the slice
+    /// dimension in `dim_info`.
+    ///
+    /// Slices outside of `slice_start` and `slice_end` are not corrected. If
+    /// `dim_info` does not give the slice dimension, the third axis is
+    /// assumed. Returns an `Argument` error if the header has no slice code
+    /// or slice duration, since the timing is then unknown.
+    pub fn from_header(image: &NiftiImage) -> Result<SliceTiming> {
+        let header = image.header();
+        let axis = match (header.dim_info >> 4) & 3 {
+            0 => {
+                warn!("Slice dimension not set in header, assuming 3rd axis");
+                2
+            }
+            dim => dim as usize - 1,
+        };
+        let n_slices = image.data().shape()[axis];
+        if header.slice_code == 0 || header.slice_duration <= 0. {
+            return Err(NirustError::Argument(
+                "header has no slice code or slice duration, the slice \
+                timing must be given by a BIDS JSON sidecar"
+                    .to_string(),
+            ));
+        }
+
+        let start = header.slice_start.max(0) as usize;
+        let end = match header.slice_end {
+            end if end > 0 => end as usize,
+            _ => n_slices - 1,
+        };
+        if start > end || end >= n_slices {
+            return Err(NirustError::Argument(format!(
+                "slice range {}..={} does not fit {} slices",
+                start, end, n_slices
+            )));
+        }
+        let slices: Vec<usize> = (start..=end).collect();
+        let even = slices.iter().step_by(2);
+        let odd = slices.iter().skip(1).step_by(2);
+        let order: Vec<usize> = match header.slice_code {
+            1 => slices.clone(),
+            2 => slices.iter().rev().copied().collect(),
+            3 => even.chain(odd).copied().collect(),
+            4 => even.chain(odd).map(|s| start + end - s).collect(),
+            5 => odd.chain(even).copied().collect(),
+            6 => odd.chain(even).map(|s| start + end - s).collect(),
+            code => {
+                return Err(NirustError::Argument(format!(
+                    "unknown slice code {}",
+                    code
+                )))
+            }
+        };
+
+        // slice_duration is in the time unit of the header, like the TR
+        let scale = match header.xyzt_units & 0x38 {
+            16 => 1e-3,
+            24 => 1e-6,
+            _ => 1.,
+        };
+        let mut times = vec![f32::NAN; n_slices];
+        for (position, slice) in order.into_iter().enumerate() {
+            times[slice] = position as f32 * header.slice_duration * scale;
+        }
+        Ok(SliceTiming { times, axis })
+    }
+
+    /// Middle of the acquisition times, which is the default reference time
+    /// (as in fMRIPrep).
+    pub fn middle(&self) -> f32 {
+        let finite = self.times.iter().filter(|time| time.is_finite());
+        let min = finite.clone().copied().fold(f32::INFINITY, f32::min);
+        let max = finite.copied().fold(f32::NEG_INFINITY, f32::max);
+        (min + max) / 2.
+    }
+}
+
+/// Correct a 4D image for the acquisition times of its slices.
+///
+/// The time series of every slice is interpolated to the reference time, so
+/// that all voxels of a volume correspond to the same point in time. The
+/// header of the image is kept.
+///
+/// Parameters
+/// ----------
+/// image : 4D image with a repetition time in its header.
+///
+/// timing : Acquisition times of the slices, see `SliceTiming`.
+///
+/// reference_time : Time (seconds) within the repetition time to which the
+/// slices are interpolated, by default the middle of the acquisition times.
+///
+/// interpolation : Linear or sinc interpolation of the time series.
+pub fn slice_time_correct(
+    image: &NiftiImage,
+    timing: &SliceTiming,
+    reference_time: Option<f32>,
+    interpolation: SliceInterpolation,
+) -> Result<NiftiImage> {
+    if image.ndim() != 4 {
+        return Err(NirustError::Shape(format!(
+            "slice timing correction requires a 4D image, got a {}D image",
+            image.ndim()
+        )));
+    }
+    if image.n_volumes() == 0 {
+        return Err(NirustError::Shape(
+            "slice timing correction requires at least one volume".to_string(),
+        ));
+    }
+    let tr = image.tr().ok_or_else(|| {
+        NirustError::Argument("image has no repetition time".to_string())
+    })?;
+    if timing.axis > 2
+        || timing.times.len() != image.data().shape()[timing.axis]
+    {
+        return Err(NirustError::Shape(format!(
+            "got {} slice times, but the image has {:?} voxels along the \
+            slice axis",
+            timing.times.len(),
+            image.data().shape().get(timing.axis)
+        )));
+    }
+    if let Some(time) = timing
+        .times
+        .iter()
+        .find(|time| time.is_finite() && !(0. ..=tr).contains(*time))
+    {
+        return Err(NirustError::Argument(format!(
+            "slice time {} s is outside of the repetition time of {} s",
+            time, tr
+        )));
+    }
+    let reference_time = reference_time.unwrap_or_else(|| timing.middle());
+    info!(
+        "Interpolating slices to {} s with {:?} interpolation",
+        reference_time, interpolation
+    );
+
+    // shift of every slice in volumes, the corrected value of volume n is
+    // the value of the slice at n + shift
+    let shifts: Vec<f64> = timing
+        .times
+        .iter()
+        .map(|&time| ((reference_time - time) / tr) as f64)
+        .collect();
+    let data = image.data().view().into_dimensionality::<Ix4>().unwrap();
+    let mut corrected = data.to_owned();
+    let n_volumes = data.shape()[3];
+    let shift_of = |index: (usize, usize, usize)| {
+        shifts[[index.0, index.1, index.2][timing.axis]]
+    };
+    match interpolation {
+        SliceInterpolation::Linear => {
+            Zip::indexed(corrected.lanes_mut(Axis(3)))
+                .and(data.lanes(Axis(3)))
+                .par_for_each(|index, mut corrected, series| {
+                    let shift = shift_of(index);
+                    if shift.is_finite() && shift != 0. {
+                        _shift_linear(series, corrected.view_mut(), shift)
+                    }
+                });
+        }
+        SliceInterpolation::Sinc => {
+            // mirrored series, so that the signal is periodic
+            let length = 2 * n_volumes;
+            let mut planner = FftPlanner::<f64>::new();
+            let forward = planner.plan_fft_forward(length);
+            let inverse = planner.plan_fft_inverse(length);
+            Zip::indexed(corrected.lanes_mut(Axis(3)))
+                .and(data.lanes(Axis(3)))
+                .par_for_each(|index, mut corrected, series| {
+                    let shift = shift_of(index);
+                    if !shift.is_finite() || shift == 0. {
+                        return;
+                    }
+                    let mut buffer: Vec<Complex<f64>> = series
+                        .iter()
+                        .chain(series.iter().rev())
+                        .map(|&value| Complex::new(value as f64, 0.))
+                        .collect();
+                    forward.process(&mut buffer);
+                    for (k, value) in buffer.iter_mut().enumerate() {
+                        *value *= _phase_shift(k, length, shift);
+                    }
+                    inverse.process(&mut buffer);
+                    for (corrected, value) in corrected.iter_mut().zip(buffer) {
+                        *corrected = (value.re / length as f64) as f32;
+                    }
+                });
+        }
+    }
+    image.new_img_like(corrected.into_dyn())
+}
+
+// Interpolate a time series linearly at n + shift, using the first and last
+// value outside of the series.
+fn _shift_linear(
+    series: ArrayView1<f32>,
+    mut corrected: ArrayViewMut1<f32>,
+    shift: f64,
+) {
+    let last = series.len() - 1;
+    for (n, corrected) in corrected.iter_mut().enumerate() {
+        let t = (n as f64 + shift).clamp(0., last as f64);
+        let lower = t.floor() as usize;
+        let upper = (lower + 1).min(last);
+        let weight = (t - lower as f64) as f32;
+        *corrected = (1. - weight) * series[lower] + weight * series[upper];
+    }
+}
+
+// Factor of the k-th Fourier coefficient that shifts a periodic signal of the
+// given length by `shift` samples. At the Nyquist frequency only the real
+// part is kept, so that the shifted signal stays real.
+fn _phase_shift(k: usize, length: usize, shift: f64) -> Complex<f64> {
+    let frequency = match k {
+        k if 2 * k < length => k as f64,
+        k if 2 * k == length => {
+            return Complex::new((std::f64::consts::PI * shift).cos(), 0.)
+        }
+        k => k as f64 - length as f64,
+    };
+    let angle = 2. * std::f64::consts::PI * frequency * shift / length as f64;
+    Complex::new(angle.cos(), angle.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+
+    // 4D image with a TR of 1 s whose slices are along the third axis
+    fn _image(data: Array4<f32>) -> NiftiImage {
+        let header = NiftiHeader {
+            dim_info: 3 << 4,
+            pixdim: [1., 2., 2., 2., 1., 1., 1., 1.],
+            ..NiftiHeader::default()
+        };
+        NiftiImage::new(header, data.into_dyn()).unwrap()
+    }
+
+    #[test]
+    fn slice_times_follow_the_slice_code() {
+        let mut image = _image(Array4::zeros((2, 2, 6, 3)));
+        let header = image.header_mut();
+        header.slice_duration = 0.1;
+        header.slice_start = 1;
+        header.slice_end = 4;
+        // acquisition position of slices 1 to 4 for slice codes 1 to 6
+        let positions = [
+            [0, 1, 2, 3],
+            [3, 2, 1, 0],
+            [0, 2, 1, 3],
+            [3, 1, 2, 0],
+            [2, 0, 3, 1],
+            [1, 3, 0, 2],
+        ];
+        for (code, positions) in (1..=6).zip(positions) {
+            image.header_mut().slice_code = code;
+            let timing = SliceTiming::from_header(&image).unwrap();
+            assert_eq!(timing.axis, 2);
+            assert!(timing.times[0].is_nan() && timing.times[5].is_nan());
+            for (time, position) in timing.times[1..5].iter().zip(positions) {
+                assert!(
+                    (time - position as f32 * 0.1).abs() < 1e-6,
+                    "slice code {}: {:?}",
+                    code,
+                    timing.times
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn negative_encoding_direction_reverses_the_slice_times() {
+        let path = std::env::temp_dir().join("nirust_slice_timing_test.json");
+        fs::write(
+            &path,
+            r#"{"SliceTiming": [0, 0.5, 1], "SliceEncodingDirection": "k-"}"#,
+        )
+        .unwrap();
+        let timing = SliceTiming::from_json(&path).unwrap();
+        assert_eq!(timing.times, vec![1., 0.5, 0.]);
+        assert_eq!(timing.axis, 2);
+    }
+
+    #[test]
+    fn correction_recovers_a_shifted_sinusoid() {
+        // the second slice is acquired half a TR after the first one
+        let signal = |t: f32| (2. * std::f32::consts::PI * t / 16.).sin();
+        let data = Array4::from_shape_fn((1, 1, 2, 48), |(_, _, k, n)| {
+            signal(n as f32 + 0.5 * k as f32)
+        });
+        let image = _image(data);
+        let timing = SliceTiming {
+            times: vec![0., 0.5],
+            axis: 2,
+        };
+        for (interpolation, tolerance) in [
+            (SliceInterpolation::Linear, 0.02),
+            (SliceInterpolation::Sinc, 0.005),
+        ] {
+            let corrected =
+                slice_time_correct(&image, &timing, Some(0.), interpolation)
+                    .unwrap();
+            // the edges of the series are extrapolated
+            for n in 4..44 {
+                let value = corrected.data()[[0, 0, 1, n]];
+                assert!(
+                    (value - signal(n as f32)).abs() < tolerance,
+                    "{:?} at {}: {} != {}",
+                    interpolation,
+                    n,
+                    value,
+                    signal(n as f32)
+                );
+                assert_eq!(
+                    corrected.data()[[0, 0, 0, n]],
+                    image.data()[[0, 0, 0, n]]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn images_without_volumes_are_rejected() {
+        let image = _image(Array4::zeros((2, 2, 2, 0)));
+        let timing = SliceTiming {
+            times: vec![0., 0.5],
+            axis: 2,
+        };
+        assert!(matches!(
+            slice_time_correct(
+                &image,
+                &timing,
+                None,
+                SliceInterpolation::Linear
+            ),
+            Err(NirustError::Shape(_))
+        ));
+    }
+}
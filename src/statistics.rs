@@ -5,6 +5,9 @@
 use log::info;
 use ndarray::prelude::*;
 
+use crate::error::{NirustError, Result};
+use crate::image::{Dtype, NiftiImage};
+
 /// Compute the temporal signal-to-noise ratio for every voxel
 ///
 /// The temporal signal-to-noise ratio is defined as the mean signal divided
@@ -12,27 +15,35 @@ use ndarray::prelude::*;
 ///
 /// Parameters
 /// ----------
-/// image_data : 4D ndarray containing the voxelwise image data, with the last
-/// dimension corresponding to the time dimension. 
-pub fn voxelwise_tsnr(image_data: Array<f32, IxDyn>) -> Array<f32, IxDyn> {
+/// image : 4D image, with the last dimension corresponding to the time
+/// dimension.
+///
+/// Returns a 3D image with the voxel-wise tSNR.
+pub fn voxelwise_tsnr(image: &NiftiImage) -> Result<NiftiImage> {
+    let image_data = image.data();
     let dims = image_data.shape();
     let n_dims = dims.len();
 
     // Validate input
     if n_dims != 4 {
-        panic!(
-            r"Temporal SNR can only be calculated 
-            for images with a temporal dimension."
-        );
+        return Err(NirustError::Shape(format!(
+            "temporal SNR can only be calculated for 4D images with a \
+            temporal dimension, got a {}D image",
+            n_dims
+        )));
     }
 
     info!("Calculating voxel-wise mean along time axis...");
-    let mean_img = image_data.mean_axis(Axis(3)).unwrap();
+    let mean_img = image_data.mean_axis(Axis(3)).ok_or_else(|| {
+        NirustError::Shape("image has no volumes along time axis".to_string())
+    })?;
 
     info!("Calculating voxel-wise standard deviation along time axis...");
     let std_img = image_data.std_axis(Axis(3), 1.);
 
     info!("Calculating tSNR...");
 
-    mean_img / std_img
+    let mut tsnr = image.new_img_like(mean_img / std_img)?;
+    tsnr.set_dtype(Dtype::Float32);
+    Ok(tsnr)
 }
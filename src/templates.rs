@@ -0,0 +1,159 @@
+//! The `nirust::templates` module defines the grids (shape and affine) of
+//! common standard templates, so that images can be resampled to a template
+//! space without a reference image on disk.
+//!
+//! The 1 and 2 mm grids of MNI152NLin2009cAsym are those of
+//! [TemplateFlow](https://www.templateflow.org) (as used by fMRIPrep), the
+//! 1 and 2 mm grids of MNI152NLin6Asym those of the FSL MNI152 templates. The
+//! 3 mm grids cover the same field of view as the 1 mm grids.
+
+use ndarray::prelude::*;
+
+use crate::error::{NirustError, Result};
+use crate::image::{resample_img, Interpolation, NiftiImage};
+
+// NIfTI xform code of coordinates in MNI152 space
+const MNI152_CODE: i16 = 4;
+
+/// Shape and affine of a standard template grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemplateGrid {
+    /// Name of the grid, e.g. "MNI152NLin2009cAsym_2mm".
+    pub name: &'static str,
+    pub shape: (usize, usize, usize),
+    /// Signed voxel sizes along x, y and z.
+    pub zooms: [f32; 3],
+    /// World coordinates of the first voxel.
+    pub origin: [f32; 3],
+}
+
+/// All built-in template grids.
+pub const TEMPLATE_GRIDS: [TemplateGrid; 6] = [
+    TemplateGrid {
+        name: "MNI152NLin2009cAsym_1mm",
+        shape: (193, 229, 193),
+        zooms: [1., 1., 1.],
+        origin: [-96., -132., -78.],
+    },
+    TemplateGrid {
+        name: "MNI152NLin2009cAsym_2mm",
+        shape: (97, 115, 97),
+        zooms: [2., 2., 2.],
+        origin: [-96., -132., -78.],
+    },
+    TemplateGrid {
+        name: "MNI152NLin2009cAsym_3mm",
+        shape: (65, 77, 65),
+        zooms: [3., 3., 3.],
+        origin: [-96., -132., -78.],
+    },
+    TemplateGrid {
+        name: "MNI152NLin6Asym_1mm",
+        shape: (182, 218, 182),
+        zooms: [-1., 1., 1.],
+        origin: [90., -126., -72.],
+    },
+    TemplateGrid {
+        name: "MNI152NLin6Asym_2mm",
+        shape: (91, 109, 91),
+        zooms: [-2., 2., 2.],
+        origin: [90., -126., -72.],
+    },
+    TemplateGrid {
+        name: "MNI152NLin6Asym_3mm",
+        shape: (61, 73, 61),
+        zooms: [-3., 3., 3.],
+        origin: [90., -126., -72.],
+    },
+];
+
+impl TemplateGrid {
+    /// Find a built-in grid by its name (case-insensitive), e.g.
+    /// "MNI152NLin6Asym_2mm".
+    pub fn from_name(name: &str) -> Option<TemplateGrid> {
+        TEMPLATE_GRIDS
+            .into_iter()
+            .find(|grid| grid.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Affine of the grid.
+    pub fn affine(&self) -> Array2<f32> {
+        let mut affine = Array2::<f32>::eye(4);
+        for axis in 0..3 {
+            affine[[axis, axis]] = self.zooms[axis];
+            affine[[axis, 3]] = self.origin[axis];
+        }
+        affine
+    }
+}
+
+/// Resample a 3D or 4D image to a built-in template grid.
+///
+/// Parameters
+/// ----------
+/// source : 3D or 4D image to be resampled.
+///
+/// template : Name of the template grid, see `TEMPLATE_GRIDS`.
+///
+/// interpolation : How to obtain values between the source voxels.
+///
+/// fill_value : Value of voxels outside of the field of view of `source`.
+///
+/// The sform and qform codes of the resampled image are set to MNI152.
+///
+/// Returns an `Argument` error if there is no template grid with that name.
+pub fn resample_to_template(
+    source: &NiftiImage,
+    template: &str,
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    let grid = TemplateGrid::from_name(template).ok_or_else(|| {
+        let names: Vec<&str> =
+            TEMPLATE_GRIDS.iter().map(|grid| grid.name).collect();
+        NirustError::Argument(format!(
+            "unknown template '{}', expected one of {}",
+            template,
+            names.join(", ")
+        ))
+    })?;
+    let mut resampled = resample_img(
+        source,
+        &grid.affine(),
+        grid.shape,
+        interpolation,
+        fill_value,
+    )?;
+    let header = resampled.header_mut();
+    header.sform_code = MNI152_CODE;
+    header.qform_code = MNI152_CODE;
+    Ok(resampled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+
+    #[test]
+    fn resampled_images_are_in_mni152_space() {
+        let header = NiftiHeader {
+            sform_code: 1,
+            qform_code: 1,
+            ..NiftiHeader::default()
+        };
+        let data = Array::ones((2, 2, 2)).into_dyn();
+        let source = NiftiImage::new(header, data).unwrap();
+        let resampled = resample_to_template(
+            &source,
+            "MNI152NLin2009cAsym_3mm",
+            Interpolation::Nearest,
+            0.,
+        )
+        .unwrap();
+        let grid = TemplateGrid::from_name("MNI152NLin2009cAsym_3mm").unwrap();
+        assert_eq!(resampled.affine(), grid.affine());
+        assert_eq!(resampled.header().sform_code, MNI152_CODE);
+        assert_eq!(resampled.header().qform_code, MNI152_CODE);
+    }
+}
@@ -0,0 +1,648 @@
+//! The `nirust::transforms` module reads the linear transforms written by
+//! registration tools (FSL FLIRT, ANTs/ITK and AFNI) and applies them when
+//! resampling an image onto the grid of a reference image.
+//!
+//! Every tool stores its transforms in its own convention. nirust converts
+//! them to 4x4 affines that map the RAS world coordinates (as given by the
+//! affines of the images, see `get_affine`) of the reference image to those
+//! of the moving image. Like in nitransforms, the transforms thus "pull" the
+//! moving image onto the reference grid, which is what resampling needs.
+
+use ndarray::prelude::*;
+use ndarray_linalg::solve::Inverse;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{NirustError, Result};
+use crate::image::{
+    load_img, resample_to_img_with_affine, DisplacementField, Interpolation,
+    NiftiImage,
+};
+
+// signs of the world axes when converting between LPS and RAS coordinates
+const LPS_TO_RAS: [f32; 4] = [-1., -1., 1., 1.];
+
+// intent codes of the spline and DCT coefficient files written by FNIRT
+const FSL_COEFFICIENT_INTENTS: std::ops::RangeInclusive<i16> = 2007..=2009;
+
+/// File formats of linear transforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformFormat {
+    /// FSL FLIRT matrix (`.mat` text file), which maps the scaled voxel
+    /// coordinates of the moving image to those of the reference image.
+    Fsl,
+    /// ITK transform as written by ANTs, either as text file (`.txt`,
+    /// `.tfm`) or as binary MATLAB file (`.mat`). It maps LPS world
+    /// coordinates of the reference image to those of the moving image.
+    Itk,
+    /// AFNI matrix (`.1D`) with 12 values, e.g. written by `3dAllineate
+    /// -1Dmatrix_save`. It maps LPS world coordinates of the reference
+    /// (base) image to those of the moving (source) image.
+    Afni,
+    /// 4x4 affine in the convention of nirust, i.e. mapping RAS world
+    /// coordinates of the reference image to those of the moving image.
+    Ras,
+}
+
+impl TransformFormat {
+    /// Guess the format of a transform file from its extension and, for
+    /// `.mat` files (used as text files by FSL and as binary files by ANTs)
+    /// and `.txt` files, from its content.
+    ///
+    /// `.txt` files are only recognized as ITK transforms. Other text files,
+    /// e.g. 4x4 affines, can be in any convention, so their format has to be
+    /// given explicitly.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".1d") {
+            Ok(TransformFormat::Afni)
+        } else if name.ends_with(".tfm") {
+            Ok(TransformFormat::Itk)
+        } else if name.ends_with(".txt") {
+            let text = fs::read_to_string(path)?;
+            if text.starts_with("#Insight Transform File") {
+                Ok(TransformFormat::Itk)
+            } else {
+                Err(NirustError::Argument(format!(
+                    "{:?} is not an ITK transform file, please specify its \
+                    format, e.g. --format ras for a 4x4 affine",
+                    path
+                )))
+            }
+        } else if name.ends_with(".mat") {
+            match _is_binary(&fs::read(path)?) {
+                false => Ok(TransformFormat::Fsl),
+                true => Ok(TransformFormat::Itk),
+            }
+        } else {
+            Err(NirustError::Argument(format!(
+                "cannot guess the format of the transform {:?} from its \
+                extension, please specify it",
+                path
+            )))
+        }
+    }
+}
+
+impl FromStr for TransformFormat {
+    type Err = String;
+
+    /// Parse "fsl", "itk" (or "ants"), "afni" or "ras".
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fsl" => Ok(TransformFormat::Fsl),
+            "itk" | "ants" => Ok(TransformFormat::Itk),
+            "afni" => Ok(TransformFormat::Afni),
+            "ras" => Ok(TransformFormat::Ras),
+            _ => Err(format!(
+                "unknown transform format '{}', expected 'fsl', 'itk', \
+                'afni' or 'ras'",
+                s
+            )),
+        }
+    }
+}
+
+/// Read a linear transform and convert it to an affine mapping the RAS world
+/// coordinates of `reference` to those of `moving`.
+///
+/// Parameters
+/// ----------
+/// path : Path to the transform file.
+///
+/// format : Format of the transform file, see `TransformFormat::from_path`
+/// to guess it.
+///
+/// moving : Image the transform was estimated for, i.e. the image to be
+/// resampled. Only used for FSL matrices, which depend on the voxel sizes,
+/// shape and orientation of the images.
+///
+/// reference : Image the moving image was registered to. Only used for FSL
+/// matrices.
+///
+pub fn read_transform(
+    path: &Path,
+    format: TransformFormat,
+    moving: &NiftiImage,
+    reference: &NiftiImage,
+) -> Result<Array2<f32>> {
+    match format {
+        TransformFormat::Fsl => {
+            _fsl_to_ras(&read_affine(path)?, moving, reference)
+        }
+        TransformFormat::Itk => {
+            let (parameters, center) = _read_itk(path)?;
+            _itk_to_ras(&parameters, &center)
+                .map_err(|msg| _invalid(path, &msg))
+        }
+        TransformFormat::Afni => {
+            let values = _read_values(path)?;
+            if values.len() != 12 {
+                return Err(_invalid(
+                    path,
+                    &format!(
+                        "expected 12 values (one transform), got {}",
+                        values.len()
+                    ),
+                ));
+            }
+            let mut lps = Array2::<f32>::eye(4);
+            for (i, v) in values.iter().enumerate() {
+                lps[[i / 4, i % 4]] = *v;
+            }
+            Ok(_lps_to_ras(&lps))
+        }
+        TransformFormat::Ras => read_affine(path),
+    }
+}
+
+/// Read a displacement field and convert its vectors to RAS world
+/// coordinates.
+///
+/// ANTs/ITK and AFNI store displacements in LPS world coordinates, as 5D
+/// images with shape (x, y, z, 1, 3) and 4D images respectively. FSL stores
+/// them as 4D images in its scaled voxel coordinates; FNIRT coefficient files
+/// have to be converted to relative displacement fields first, e.g. with
+/// `fnirtfileutils` or `convertwarp --relout`.
+///
+/// Parameters
+/// ----------
+/// path : Path to the image with the displacement field.
+///
+/// format : Format of the field, or `None` to assume ITK for 5D and FSL for
+/// 4D images.
+///
+pub fn read_displacement_field(
+    path: &Path,
+    format: Option<TransformFormat>,
+) -> Result<DisplacementField> {
+    let image = load_img(path)?;
+    let intent_code = image.header().intent_code;
+    if FSL_COEFFICIENT_INTENTS.contains(&intent_code) {
+        return Err(_invalid(
+            path,
+            "FNIRT coefficient files must be converted to a displacement \
+            field first, e.g. with convertwarp --relout",
+        ));
+    }
+    let format = format.unwrap_or(match image.ndim() {
+        5 => TransformFormat::Itk,
+        _ => TransformFormat::Fsl,
+    });
+    let to_ras = match format {
+        TransformFormat::Itk | TransformFormat::Afni => {
+            Array2::from_diag(&array![-1., -1., 1.])
+        }
+        TransformFormat::Fsl => {
+            // FSL vectors are in mm along the axes of the FSL coordinates
+            let fsl_inv = _inverse(
+                &_fsl_coordinates(&image),
+                "FSL coordinates of the field",
+            )?;
+            image
+                .affine()
+                .slice(s![0..3, 0..3])
+                .dot(&fsl_inv.slice(s![0..3, 0..3]))
+        }
+        TransformFormat::Ras => Array2::eye(3),
+    };
+    DisplacementField::from_img(&image, &to_ras)
+}
+
+/// Read a 4x4 affine from a text file with one row per line, e.g. written
+/// with numpy.savetxt. The values can be separated by whitespace or commas,
+/// and the last row can be omitted.
+pub fn read_affine(path: &Path) -> Result<Array2<f32>> {
+    let values = _read_values(path)?;
+    let mut affine = Array2::<f32>::eye(4);
+    match values.len() {
+        12 | 16 => {
+            for (i, v) in values.iter().take(12).enumerate() {
+                affine[[i / 4, i % 4]] = *v;
+            }
+            Ok(affine)
+        }
+        n => Err(_invalid(
+            path,
+            &format!("expected 12 or 16 values, got {}", n),
+        )),
+    }
+}
+
+/// Write a 4x4 affine to a text file with one row per line, which can be
+/// read with `read_affine` (or numpy.loadtxt).
+pub fn write_affine(path: &Path, affine: &Array2<f32>) -> Result<()> {
+    let text: String = affine
+        .rows()
+        .into_iter()
+        .map(|row| {
+            let values: Vec<String> =
+                row.iter().map(|v| format!("{:.8}", v)).collect();
+            values.join(" ") + "\n"
+        })
+        .collect();
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// Resample an image onto the grid of a reference image after applying a
+/// linear transform, in a single interpolation step.
+///
+/// The resampled image has the spatial shape and affine of `reference`, see
+/// `resample_to_img`.
+///
+/// Parameters
+/// ----------
+/// moving : 3D or 4D image to be resampled.
+///
+/// reference : Image whose grid is to be matched.
+///
+/// transform : 4x4 affine mapping RAS world coordinates of `reference` to
+/// those of `moving`, e.g. read with `read_transform`.
+///
+/// interpolation : How to obtain values between the voxels of `moving`.
+///
+/// fill_value : Value of voxels outside of the field of view of `moving`.
+///
+pub fn apply_transform(
+    moving: &NiftiImage,
+    reference: &NiftiImage,
+    transform: &Array2<f32>,
+    interpolation: Interpolation,
+    fill_value: f32,
+) -> Result<NiftiImage> {
+    if transform.dim() != (4, 4) {
+        return Err(NirustError::Affine(format!(
+            "transform must be a 4x4 matrix, got {:?}",
+            transform.dim()
+        )));
+    }
+    // the affine of the moving image in the world space of the reference
+    let moved_affine = _inverse(transform, "transform")?.dot(moving.affine());
+    resample_to_img_with_affine(
+        moving,
+        &moved_affine,
+        None,
+        reference,
+        interpolation,
+        fill_value,
+    )
+}
+
+// Convert a FLIRT matrix, which maps the FSL coordinates of the moving image
+// to those of the reference image.
+fn _fsl_to_ras(
+    matrix: &Array2<f32>,
+    moving: &NiftiImage,
+    reference: &NiftiImage,
+) -> Result<Array2<f32>> {
+    let moving_to_reference = reference
+        .affine()
+        .dot(&_inverse(&_fsl_coordinates(reference), "FSL coordinates")?)
+        .dot(matrix)
+        .dot(&_fsl_coordinates(moving))
+        .dot(&_inverse(moving.affine(), "affine of the moving image")?);
+    _inverse(&moving_to_reference, "FSL transform")
+}
+
+// Affine from voxel indices to the coordinates used by FSL: voxel indices
+// scaled by the voxel sizes, with the first axis flipped for images whose
+// affine has a positive determinant (neurological voxel order).
+fn _fsl_coordinates(image: &NiftiImage) -> Array2<f32> {
+    let (zx, zy, zz) = image.voxel_sizes();
+    let mut coordinates = Array2::from_diag(&array![zx, zy, zz, 1.]);
+    if _determinant_3x3(image.affine()) > 0. {
+        let (nx, _, _) = image.spatial_shape();
+        coordinates[[0, 0]] = -zx;
+        coordinates[[0, 3]] = (nx - 1) as f32 * zx;
+    }
+    coordinates
+}
+
+fn _determinant_3x3(a: &Array2<f32>) -> f32 {
+    a[[0, 0]] * (a[[1, 1]] * a[[2, 2]] - a[[1, 2]] * a[[2, 1]])
+        - a[[0, 1]] * (a[[1, 0]] * a[[2, 2]] - a[[1, 2]] * a[[2, 0]])
+        + a[[0, 2]] * (a[[1, 0]] * a[[2, 1]] - a[[1, 1]] * a[[2, 0]])
+}
+
+// Convert the parameters of an ITK affine transform, i.e. the 3x3 matrix
+// (row-major) followed by the translation, and its center of rotation.
+fn _itk_to_ras(
+    parameters: &[f64],
+    center: &[f64],
+) -> std::result::Result<Array2<f32>, String> {
+    if parameters.len() != 12 {
+        return Err(format!(
+            "expected 12 affine parameters, got {}",
+            parameters.len()
+        ));
+    }
+    let center = match center.len() {
+        0 => [0.; 3],
+        3 => [center[0], center[1], center[2]],
+        n => return Err(format!("expected 3 fixed parameters, got {}", n)),
+    };
+    // ITK maps x to M (x - c) + t + c
+    let mut lps = Array2::<f32>::eye(4);
+    for row in 0..3 {
+        let mut offset = parameters[9 + row] + center[row];
+        for col in 0..3 {
+            let m = parameters[3 * row + col];
+            lps[[row, col]] = m as f32;
+            offset -= m * center[col];
+        }
+        lps[[row, 3]] = offset as f32;
+    }
+    Ok(_lps_to_ras(&lps))
+}
+
+fn _lps_to_ras(affine: &Array2<f32>) -> Array2<f32> {
+    Array2::from_shape_fn((4, 4), |(row, col)| {
+        affine[[row, col]] * LPS_TO_RAS[row] * LPS_TO_RAS[col]
+    })
+}
+
+// Read the affine parameters and the center of an ITK transform file, which
+// is either a text file or a binary MATLAB file.
+fn _read_itk(path: &Path) -> Result<(Vec<f64>, Vec<f64>)> {
+    let bytes = fs::read(path)?;
+    let (name, parameters, center) = if !_is_binary(&bytes) {
+        _parse_itk_text(&String::from_utf8_lossy(&bytes))
+            .map_err(|msg| _invalid(path, &msg))?
+    } else {
+        let variables = _read_matlab_v4(&bytes)
+            .ok_or_else(|| _invalid(path, "invalid binary MATLAB file"))?;
+        let mut transform = None;
+        let mut center = Vec::new();
+        for (name, values) in variables {
+            if name == "fixed" {
+                center = values;
+            } else {
+                transform = Some((name, values));
+            }
+        }
+        let (name, parameters) = transform
+            .ok_or_else(|| _invalid(path, "no transform parameters"))?;
+        (name, parameters, center)
+    };
+    if !_is_affine_transform(&name) {
+        return Err(_invalid(
+            path,
+            &format!(
+                "transforms of type {} are not supported, only 3D affine \
+                transforms",
+                name
+            ),
+        ));
+    }
+    Ok((parameters, center))
+}
+
+// Type, parameters and fixed parameters of the single transform of an ITK
+// text file.
+type ItkTransform = (String, Vec<f64>, Vec<f64>);
+
+fn _parse_itk_text(text: &str) -> std::result::Result<ItkTransform, String> {
+    let parse = |values: &str| {
+        values
+            .split_whitespace()
+            .map(|v| v.parse::<f64>().map_err(|e| e.to_string()))
+            .collect::<std::result::Result<Vec<f64>, String>>()
+    };
+    let mut transforms: Vec<ItkTransform> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("Transform:") {
+            transforms.push((name.trim().to_string(), Vec::new(), Vec::new()));
+        } else if let Some(values) = line.strip_prefix("Parameters:") {
+            if let Some(transform) = transforms.last_mut() {
+                transform.1 = parse(values)?;
+            }
+        } else if let Some(values) = line.strip_prefix("FixedParameters:") {
+            if let Some(transform) = transforms.last_mut() {
+                transform.2 = parse(values)?;
+            }
+        }
+    }
+    // composite transforms (as written by antsRegistration) list their
+    // components after a header transform without parameters
+    transforms.retain(|(name, _, _)| !name.starts_with("CompositeTransform"));
+    match transforms.len() {
+        1 => Ok(transforms.remove(0)),
+        0 => Err("no transform found".to_string()),
+        n => Err(format!("expected a single transform, found {}", n)),
+    }
+}
+
+// Text transform files never contain NUL bytes, unlike MATLAB files.
+fn _is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
+}
+
+fn _is_affine_transform(name: &str) -> bool {
+    (name.starts_with("AffineTransform_")
+        || name.starts_with("MatrixOffsetTransformBase_"))
+        && name.ends_with("_3_3")
+}
+
+// Read the variables of a MATLAB v4 file, as written by ITK for the binary
+// `.mat` transforms of ANTs. Returns `None` if the file is not valid.
+fn _read_matlab_v4(bytes: &[u8]) -> Option<Vec<(String, Vec<f64>)>> {
+    let mut variables = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let head = bytes.get(pos..pos + 20)?;
+        let int = |i: usize, big_endian: bool| {
+            let word: [u8; 4] = head[4 * i..4 * i + 4].try_into().unwrap();
+            match big_endian {
+                true => i32::from_be_bytes(word),
+                false => i32::from_le_bytes(word),
+            }
+        };
+        // the type is a number MOPT, where M is the byte order, P the
+        // precision and O and T are zero for numeric matrices
+        let big_endian = !(0..1000).contains(&int(0, false));
+        let mopt = int(0, big_endian);
+        if mopt / 1000 != big_endian as i32 || mopt % 1000 / 100 != 0 {
+            return None;
+        }
+        let size = match (mopt % 100 / 10, mopt % 10) {
+            (0, 0) => 8,
+            (1, 0) => 4,
+            _ => return None,
+        };
+        let n_values = usize::try_from(int(1, big_endian)).ok()?
+            * usize::try_from(int(2, big_endian)).ok()?;
+        let is_complex = int(3, big_endian) != 0;
+        let name_length = usize::try_from(int(4, big_endian)).ok()?;
+
+        let name = bytes.get(pos + 20..pos + 20 + name_length)?;
+        let name = String::from_utf8_lossy(name)
+            .trim_end_matches('\0')
+            .to_string();
+        let start = pos + 20 + name_length;
+        let data = bytes.get(start..start + n_values * size)?;
+        let values = data
+            .chunks_exact(size)
+            .map(|chunk| match (size, big_endian) {
+                (8, true) => f64::from_be_bytes(chunk.try_into().unwrap()),
+                (8, false) => f64::from_le_bytes(chunk.try_into().unwrap()),
+                (_, true) => {
+                    f32::from_be_bytes(chunk.try_into().unwrap()) as f64
+                }
+                (_, false) => {
+                    f32::from_le_bytes(chunk.try_into().unwrap()) as f64
+                }
+            })
+            .collect();
+        variables.push((name, values));
+        pos = start + n_values * size * (1 + is_complex as usize);
+    }
+    Some(variables)
+}
+
+// Read all numbers of a text file, separated by whitespace or commas and
+// ignoring comment lines starting with '#'.
+fn _read_values(path: &Path) -> Result<Vec<f32>> {
+    let text = fs::read_to_string(path)?;
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<f32>())
+        .collect::<std::result::Result<Vec<f32>, _>>()
+        .map_err(|e| _invalid(path, &e.to_string()))
+}
+
+fn _inverse(affine: &Array2<f32>, what: &str) -> Result<Array2<f32>> {
+    affine.inv().map_err(|e| {
+        NirustError::Affine(format!("{} is singular ({})", what, e))
+    })
+}
+
+fn _invalid(path: &Path, msg: &str) -> NirustError {
+    NirustError::Format(format!("{:?} is not a valid transform: {}", path, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nifti::NiftiHeader;
+    use std::path::PathBuf;
+
+    fn _write(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn _image(affine: Array2<f32>) -> NiftiImage {
+        let data = Array::zeros(IxDyn(&[5, 4, 3]));
+        let mut image = NiftiImage::new(NiftiHeader::default(), data).unwrap();
+        image.set_affine(&affine);
+        image
+    }
+
+    fn _translation(x: f32, y: f32, z: f32) -> Array2<f32> {
+        let mut affine = Array2::eye(4);
+        affine.slice_mut(s![0..3, 3]).assign(&array![x, y, z]);
+        affine
+    }
+
+    fn _assert_close(a: &Array2<f32>, b: &Array2<f32>) {
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-5, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn txt_files_without_itk_header_need_an_explicit_format() {
+        let path = _write("nirust_transforms_test_affine.txt", "1 0 0 0\n");
+        assert!(matches!(
+            TransformFormat::from_path(&path),
+            Err(NirustError::Argument(_))
+        ));
+        let path = _write(
+            "nirust_transforms_test_itk.txt",
+            "#Insight Transform File V1.0\n",
+        );
+        assert_eq!(
+            TransformFormat::from_path(&path).unwrap(),
+            TransformFormat::Itk
+        );
+    }
+
+    #[test]
+    fn fsl_matrices_are_converted_to_ras() {
+        // radiological (LAS) and neurological (RAS) voxel orders, which
+        // differ in the FSL coordinates
+        for x_zoom in [-2., 2.] {
+            let image = _image(arr2(&[
+                [x_zoom, 0., 0., 10.],
+                [0., 2., 0., -20.],
+                [0., 0., 2., 30.],
+                [0., 0., 0., 1.],
+            ]));
+            let path = _write(
+                "nirust_transforms_test_identity.mat",
+                "1 0 0 0\n0 1 0 0\n0 0 1 0\n0 0 0 1\n",
+            );
+            let ras =
+                read_transform(&path, TransformFormat::Fsl, &image, &image)
+                    .unwrap();
+            _assert_close(&ras, &Array2::eye(4));
+
+            // FSL coordinates have a flipped x axis relative to RAS world
+            // coordinates for both voxel orders
+            let path = _write(
+                "nirust_transforms_test_translation.mat",
+                "1 0 0 4\n0 1 0 -6\n0 0 1 8\n0 0 0 1\n",
+            );
+            let ras =
+                read_transform(&path, TransformFormat::Fsl, &image, &image)
+                    .unwrap();
+            _assert_close(&ras, &_translation(4., 6., -8.));
+        }
+    }
+
+    #[test]
+    fn itk_transforms_are_converted_from_lps_to_ras() {
+        let image = _image(Array2::eye(4));
+        let itk = |parameters: &str| {
+            let path = _write(
+                "nirust_transforms_test_itk.tfm",
+                &format!(
+                    "#Insight Transform File V1.0\n#Transform 0\n\
+                    Transform: AffineTransform_double_3_3\n\
+                    Parameters: {}\nFixedParameters: 10 -20 30\n",
+                    parameters
+                ),
+            );
+            read_transform(&path, TransformFormat::Itk, &image, &image).unwrap()
+        };
+        // the center of rotation has no effect without a rotation
+        _assert_close(&itk("1 0 0 0 1 0 0 0 1 0 0 0"), &Array2::eye(4));
+        _assert_close(
+            &itk("1 0 0 0 1 0 0 0 1 5 -3 2"),
+            &_translation(-5., 3., 2.),
+        );
+    }
+
+    #[test]
+    fn afni_matrices_are_converted_from_lps_to_ras() {
+        let image = _image(Array2::eye(4));
+        let afni = |values: &str| {
+            let path = _write("nirust_transforms_test_afni.1D", values);
+            read_transform(&path, TransformFormat::Afni, &image, &image)
+                .unwrap()
+        };
+        _assert_close(&afni("1 0 0 0 0 1 0 0 0 0 1 0"), &Array2::eye(4));
+        _assert_close(
+            &afni("1 0 0 5 0 1 0 -3 0 0 1 2"),
+            &_translation(-5., 3., 2.),
+        );
+    }
+}